@@ -18,6 +18,8 @@ pub enum Pattern {
     Begin,
     End,
     Expression(Expression),
+    // pat1, pat2
+    Range(Expression, Expression),
 }
 
 #[derive(Debug, PartialEq)]
@@ -31,11 +33,27 @@ pub struct Function {
 #[derive(Debug, PartialEq, Clone)]
 pub enum Expression {
     Value(Value),
+    // /ere/
+    Regex(String),
     BinaryOp {
         op: BOperator,
         left: Box<Expression>,
         right: Box<Expression>,
     },
+    Unary {
+        op: UOperator,
+        expr: Box<Expression>,
+    },
+    Ternary {
+        cond: Box<Expression>,
+        then: Box<Expression>,
+        els: Box<Expression>,
+    },
+    // (key) in array
+    In {
+        expr_list: Vec<Expression>,
+        name: String,
+    },
     IncDec {
         op: IncDecType,
         lval: Box<LValue>,
@@ -46,6 +64,18 @@ pub enum Expression {
         lval: LValue,
         expr: Box<Expression>,
     },
+    // lval op= expr
+    OpAssign {
+        op: BOperator,
+        lval: LValue,
+        expr: Box<Expression>,
+    },
+    // getline [var] [< file] / cmd | getline [var]
+    Getline {
+        kind: GetlineType,
+        src: Option<Box<Expression>>,
+        lval: Option<LValue>,
+    },
     CallIFunc {
         name: String,
         args: Vec<Expression>,
@@ -63,6 +93,8 @@ pub enum LValue {
         name: String,
         expr_list: Vec<Expression>,
     },
+    // $expr
+    Field(Box<Expression>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -82,6 +114,22 @@ pub enum BOperator {
     Equal,            // ==
     GreaterThan,      // >
     GreaterEqualThan, // >=
+    Match,            // ~
+    NotMatch,         // !~
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum GetlineType {
+    Main,    // getline
+    File,    // getline < file
+    Command, // cmd | getline
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum UOperator {
+    Minus, // -
+    Plus,  // +
+    Not,   // !
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -92,25 +140,52 @@ pub enum IncDecType {
     PreDec,  // --lvalue
 }
 
+// print > file などの出力先
+#[derive(Debug, PartialEq)]
+pub struct Redirect {
+    pub kind: RedirectType,
+    pub dest: Expression,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RedirectType {
+    File,   // > file
+    Append, // >> file
+    Pipe,   // | cmd
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Statement {
     Action(Vec<Statement>),
-    Print(Vec<Expression>),
+    Print {
+        args: Vec<Expression>,
+        redirect: Option<Redirect>,
+    },
     Printf {
         fmt: Box<Expression>,
         args: Vec<Expression>,
+        redirect: Option<Redirect>,
     },
     Expression(Expression),
     While {
         exp: Expression,
         stat: Box<Statement>,
     },
+    DoWhile {
+        stat: Box<Statement>,
+        exp: Expression,
+    },
     For {
         init: Box<Statement>,
         test: Expression,
         updt: Box<Statement>,
         stat: Box<Statement>,
     },
+    ForIn {
+        var: String,
+        name: String,
+        stat: Box<Statement>,
+    },
     If {
         cond: Expression,
         stat: Box<Statement>,
@@ -123,11 +198,17 @@ pub enum Statement {
     Return(Expression),
     Break,
     Continue,
+    Delete(LValue),
+    Next,
+    NextFile,
+    Exit(Option<Expression>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     Num(f64),
     Str(String),
+    // 入力から来た文字列 (数値に見えれば数値として比較する)
+    StrNum(String),
     None,
 }
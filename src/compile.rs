@@ -4,7 +4,7 @@ use std::collections::HashMap;
 use crate::ast;
 use crate::ast::Value;
 use crate::ifunc;
-use crate::vm::Opcode;
+use crate::vm::{Opcode, SetTarget, SpecialVar, BUILTIN_ARRAYS};
 
 pub type VMProgram = Vec<Opcode>;
 type Asm = Vec<OpcodeL>;
@@ -12,10 +12,16 @@ type Asm = Vec<OpcodeL>;
 struct CompileEnv {
     // while文が使ったラベルのカウント
     while_label_count: usize,
+    // do-while文が使ったラベルのカウント
+    do_label_count: usize,
     // for文が使ったラベルのカウント
     for_label_count: usize,
+    // for (key in array)文が使ったラベルのカウント
+    forin_label_count: usize,
     // if文が使ったラベルのカウント
     if_label_count: usize,
+    // && と || が使ったラベルのカウント
+    logical_label_count: usize,
     // 登場する変数の一覧
     variables: IndexSet<String>,
     // 登場する関数の一覧
@@ -25,11 +31,23 @@ struct CompileEnv {
     // break, continueのジャンプ先
     // >0でwhile<0でfor
     break_continue: Vec<BCLabel>,
+    // コンパイル中のaction (next が使えるかの判定に使う)
+    context: Context,
+}
+
+#[derive(PartialEq)]
+enum Context {
+    Begin,
+    Main,
+    End,
+    Function,
 }
 
 enum BCLabel {
     For(usize),
+    ForIn(usize),
     While(usize),
+    DoWhile(usize),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -37,12 +55,15 @@ enum OpcodeL {
     End,
     Push(Value),
     Pop,
+    Dup,
     Jump(String),
     If(String),
     NIf(String),
     Call(usize),
     CallUserFunc(String),
     Return,
+    ExitCode,
+    Exit(String),
     // Expression
     Add,
     Sub,
@@ -50,33 +71,64 @@ enum OpcodeL {
     Div,
     Pow,
     Mod,
+    Neg,
+    Plus,
+    Not,
     Cat,
-    And,
-    Or,
     LessThan,
     LessEqualThan,
     NotEqual,
     Equal,
     GreaterThan,
     GreaterEqualThan,
+    Match,
+    NotMatch,
     // AWK
     Readline,
+    NextFile,
+    Getline(ast::GetlineType),
+    GetlineVar(ast::GetlineType),
     Print(usize),
     Printf(usize),
+    Redirect(ast::RedirectType),
     GetField,
+    SetField,
+    LoadRange(usize),
+    SetRange(usize, bool),
     // Variable
-    InitEnv(usize),
+    InitEnv(Vec<String>),
     InitEnvArray(usize),
     LoadVar(String),
     SetVar(String),
     LoadArray(String),
     SetArray(String),
+    InArray(String),
+    DeleteArray(String),
+    ClearArray(String),
+    FillArray(String),
+    IterInit(String),
+    IterNext(String),
+    IterEnd,
     LoadSFVar(usize),
     SetSFVar(usize),
+    LoadSpecial(SpecialVar),
+    SetSpecial(SpecialVar),
+    SetIf(SetTargetL),
+    SetRef(SetTargetL),
     // ジャンプ先を示す
     Label(String),
 }
 
+// SetIf/SetRefの代入先 (変数名はasm_to_vmprogramで解決する)
+#[derive(Debug, PartialEq, Clone)]
+enum SetTargetL {
+    Var(String),
+    SFVar(usize),
+    Special(SpecialVar),
+    Array(String),
+    Field,
+}
+
 /*
  * メモ：
  * AWKのプログラムは次のような形をしている
@@ -96,12 +148,16 @@ pub fn compile(ast: &ast::Program) -> Result<VMProgram, &str> {
     // コンパイル時環境
     let mut env = CompileEnv {
         while_label_count: 0,
+        do_label_count: 0,
         for_label_count: 0,
+        forin_label_count: 0,
         if_label_count: 0,
+        logical_label_count: 0,
         variables: IndexSet::new(),
         functions: HashMap::new(),
         func_args: vec![],
         break_continue: vec![],
+        context: Context::Begin,
     };
 
     find_user_definition_function(ast, &mut env);
@@ -120,6 +176,19 @@ pub fn compile(ast: &ast::Program) -> Result<VMProgram, &str> {
 
     compile_user_definition_function(ast, &mut asm, &mut env)?;
 
+    // PROCINFOは読み込み専用
+    if asm.iter().any(|i| match i {
+        OpcodeL::SetArray(name)
+        | OpcodeL::DeleteArray(name)
+        | OpcodeL::ClearArray(name)
+        | OpcodeL::FillArray(name)
+        | OpcodeL::SetIf(SetTargetL::Array(name))
+        | OpcodeL::SetRef(SetTargetL::Array(name)) => name == "PROCINFO",
+        _ => false,
+    }) {
+        return Err("attempt to modify read-only array `PROCINFO'");
+    }
+
     Ok(asm_to_vmprogram(&asm, &mut env))
 }
 
@@ -167,6 +236,7 @@ fn compile_user_definition_function(
     for func in functions.into_iter() {
         // 引数
         env.func_args = func.args.clone();
+        env.context = Context::Function;
         asm.push(OpcodeL::Label(format!("userfn_{}", &func.name)));
         // 関数内のactionであることを伝える方法を用意する
         compile_statement(&func.action, asm, env)?;
@@ -201,6 +271,7 @@ fn compile_all_begin_pattern(
 
     for item in items.into_iter() {
         // actionの列をコンパイル
+        env.context = Context::Begin;
         compile_statement(&item.action, asm, env)?;
     }
 
@@ -229,10 +300,17 @@ fn compile_normal_pattern(
         })
         .collect::<Vec<_>>();
 
-    if items.is_empty() {
+    // ENDがあればメインのパターンがなくても入力を最後まで読む
+    let has_end = ast.iter().any(|i| match i {
+        ast::Item::PatternAction(i) => matches!(i.pattern, ast::Pattern::End),
+        ast::Item::Function(_) => false,
+    });
+    if items.is_empty() && !has_end {
         return Ok(());
     }
 
+    env.context = Context::Main;
+
     asm.push(OpcodeL::Label("loop".to_string()));
     // 行を読み込む
     asm.push(OpcodeL::Readline);
@@ -240,19 +318,50 @@ fn compile_normal_pattern(
     asm.push(OpcodeL::If("theend".to_string()));
 
     let mut expression_index = 0;
+    let mut range_index = 0;
     for item in items.into_iter() {
-        // 式パターン
-        if let ast::Pattern::Expression(e) = &item.pattern {
-            let label = format!("exp{}", expression_index);
-            compile_expression(e, asm, env)?;
-            asm.push(OpcodeL::NIf(label.to_string()));
-            compile_statement(&item.action, asm, env)?;
-            asm.push(OpcodeL::Label(label));
-            expression_index += 1;
+        match &item.pattern {
+            // 式パターン
+            ast::Pattern::Expression(e) => {
+                let label = format!("exp{}", expression_index);
+                compile_expression(e, asm, env)?;
+                asm.push(OpcodeL::NIf(label.to_string()));
+                compile_statement(&item.action, asm, env)?;
+                asm.push(OpcodeL::Label(label));
+                expression_index += 1;
+            }
+
+            // 範囲パターン
+            //    範囲の中?──────┐YES
+            //    start?──┐NO    │
+            //    範囲に入る│     │
+            //    end?◄────┼─────┘
+            //    範囲から出る│
+            //    action   │
+            //         ◄───┘
+            // startとendが同じ行でマッチした場合はその行だけが対象になる
+            ast::Pattern::Range(start, end) => {
+                let label = format!("exp{}", expression_index);
+                asm.push(OpcodeL::LoadRange(range_index));
+                asm.push(OpcodeL::If(format!("range_in_{range_index}")));
+                compile_expression(start, asm, env)?;
+                asm.push(OpcodeL::NIf(label.to_string()));
+                asm.push(OpcodeL::SetRange(range_index, true));
+                asm.push(OpcodeL::Label(format!("range_in_{range_index}")));
+                compile_expression(end, asm, env)?;
+                asm.push(OpcodeL::NIf(format!("range_act_{range_index}")));
+                asm.push(OpcodeL::SetRange(range_index, false));
+                asm.push(OpcodeL::Label(format!("range_act_{range_index}")));
+                compile_statement(&item.action, asm, env)?;
+                asm.push(OpcodeL::Label(label));
+                expression_index += 1;
+                range_index += 1;
+            }
 
-        // Alwaysパターン
-        } else {
-            compile_statement(&item.action, asm, env)?;
+            // Alwaysパターン
+            _ => {
+                compile_statement(&item.action, asm, env)?;
+            }
         }
     }
 
@@ -281,8 +390,12 @@ fn compile_all_end_pattern(
         })
         .collect::<Vec<_>>();
 
+    // exitの飛び先
+    asm.push(OpcodeL::Label("end_pattern".to_string()));
+
     for item in items.into_iter() {
         // actionの列をコンパイル
+        env.context = Context::End;
         compile_statement(&item.action, asm, env)?;
     }
 
@@ -304,19 +417,30 @@ fn compile_statement(
         }
 
         // print文
-        ast::Statement::Print(expressions) => {
-            for e in expressions.iter() {
+        ast::Statement::Print { args, redirect } => {
+            // 引数のないprintは print $0 と同じ
+            if args.is_empty() {
+                asm.push(OpcodeL::Push(Value::Num(0.0)));
+                asm.push(OpcodeL::GetField);
+            }
+            for e in args.iter() {
                 compile_expression(e, asm, env)?;
             }
-            asm.push(OpcodeL::Print(expressions.len()));
+            compile_redirect(redirect, asm, env)?;
+            asm.push(OpcodeL::Print(args.len().max(1)));
         }
 
         // printf文
-        ast::Statement::Printf { fmt, args } => {
+        ast::Statement::Printf {
+            fmt,
+            args,
+            redirect,
+        } => {
             compile_expression(fmt, asm, env)?;
             for e in args.iter() {
                 compile_expression(e, asm, env)?;
             }
+            compile_redirect(redirect, asm, env)?;
             asm.push(OpcodeL::Printf(args.len()))
         }
 
@@ -346,6 +470,26 @@ fn compile_statement(
             env.break_continue.pop().unwrap();
         }
 
+        // do-while文
+        // ┌─►statement
+        // └──conditon?
+        ast::Statement::DoWhile { stat, exp } => {
+            let label = env.do_label_count;
+            env.do_label_count += 1;
+            env.break_continue.push(BCLabel::DoWhile(label));
+
+            asm.push(OpcodeL::Label(format!("do_s_{label}")));
+            compile_statement(stat, asm, env)?;
+            // continueの飛び先
+            asm.push(OpcodeL::Label(format!("do_c_{label}")));
+            compile_expression(exp, asm, env)?;
+            asm.push(OpcodeL::If(format!("do_s_{label}")));
+            // breakの飛び先
+            asm.push(OpcodeL::Label(format!("do_e_{label}")));
+
+            env.break_continue.pop().unwrap();
+        }
+
         // For
         //    initialize
         // ┌─►conditon?──┐
@@ -378,6 +522,59 @@ fn compile_statement(
             env.break_continue.pop().unwrap();
         }
 
+        // For In
+        // 開始時の添字の一覧に対して繰り返すので，ループ中に要素を削除してもよい
+        ast::Statement::ForIn { var, name, stat } => {
+            let label = env.forin_label_count;
+            env.forin_label_count += 1;
+            env.break_continue.push(BCLabel::ForIn(label));
+
+            asm.push(OpcodeL::IterInit(name.to_string()));
+            asm.push(OpcodeL::Label(format!("forin_s_{label}"))); // continueの飛び先
+            asm.push(OpcodeL::IterNext(format!("forin_e_{label}")));
+            compile_store_lvalue(&ast::LValue::Name(var.to_string()), asm, env)?;
+            compile_statement(stat, asm, env)?;
+            asm.push(OpcodeL::Jump(format!("forin_s_{label}")));
+            asm.push(OpcodeL::Label(format!("forin_e_{label}"))); // breakの飛び先
+            asm.push(OpcodeL::IterEnd);
+
+            env.break_continue.pop().unwrap();
+        }
+
+        // next / nextfile
+        // 次の行(ファイル)を読み込んでパターンの先頭からやり直す
+        ast::Statement::Next => {
+            match env.context {
+                Context::Begin => return Err("`next' used in BEGIN action"),
+                Context::End => return Err("`next' used in END action"),
+                Context::Function => return Err("`next' used in function"),
+                Context::Main => {}
+            }
+            asm.push(OpcodeL::Jump("loop".to_string()));
+        }
+        ast::Statement::NextFile => {
+            match env.context {
+                Context::Begin => return Err("`nextfile' used in BEGIN action"),
+                Context::End => return Err("`nextfile' used in END action"),
+                Context::Function => return Err("`nextfile' used in function"),
+                Context::Main => {}
+            }
+            asm.push(OpcodeL::NextFile);
+            asm.push(OpcodeL::Jump("loop".to_string()));
+        }
+
+        // delete array[key] / delete array
+        ast::Statement::Delete(lval) => match lval {
+            ast::LValue::Array { name, expr_list } => {
+                compile_subscript(expr_list, asm, env)?;
+                asm.push(OpcodeL::DeleteArray(name.to_string()));
+            }
+            ast::LValue::Name(name) => {
+                asm.push(OpcodeL::ClearArray(name.to_string()));
+            }
+            ast::LValue::Field(_) => return Err("delete of a field"),
+        },
+
         // If文
         ast::Statement::If { cond, stat } => {
             let label = env.if_label_count;
@@ -409,15 +606,31 @@ fn compile_statement(
             asm.push(OpcodeL::Return);
         }
 
+        // exit [expr]
+        // END以外ではENDパターンに飛び，END(から呼ばれた関数)の中では終了する
+        ast::Statement::Exit(e) => {
+            if let Some(e) = e {
+                compile_expression(e, asm, env)?;
+                asm.push(OpcodeL::ExitCode);
+            }
+            asm.push(OpcodeL::Exit("end_pattern".to_string()));
+        }
+
         ast::Statement::Break => {
             if let Some(label) = env.break_continue.last() {
                 match label {
                     BCLabel::For(l) => {
                         asm.push(OpcodeL::Jump(format!("for_e_{l}")));
                     }
+                    BCLabel::ForIn(l) => {
+                        asm.push(OpcodeL::Jump(format!("forin_e_{l}")));
+                    }
                     BCLabel::While(l) => {
                         asm.push(OpcodeL::Jump(format!("while_e_{l}")));
                     }
+                    BCLabel::DoWhile(l) => {
+                        asm.push(OpcodeL::Jump(format!("do_e_{l}")));
+                    }
                 }
             } else {
                 return Err("`break' is not allowed outside a loop");
@@ -430,9 +643,15 @@ fn compile_statement(
                     BCLabel::For(l) => {
                         asm.push(OpcodeL::Jump(format!("for_c_{l}")));
                     }
+                    BCLabel::ForIn(l) => {
+                        asm.push(OpcodeL::Jump(format!("forin_s_{l}")));
+                    }
                     BCLabel::While(l) => {
                         asm.push(OpcodeL::Jump(format!("while_s_{l}")));
                     }
+                    BCLabel::DoWhile(l) => {
+                        asm.push(OpcodeL::Jump(format!("do_c_{l}")));
+                    }
                 }
             } else {
                 return Err("`continue' is not allowed outside a loop");
@@ -454,153 +673,221 @@ fn compile_expression(
         ast::Expression::Value(v) => {
             asm.push(OpcodeL::Push(v.clone()));
         }
-        ast::Expression::BinaryOp { op, left, right } => {
+        // 単独の正規表現は $0 ~ /ere/ と同じ
+        ast::Expression::Regex(ere) => {
+            asm.push(OpcodeL::Push(Value::Num(0.0)));
+            asm.push(OpcodeL::GetField);
+            asm.push(OpcodeL::Push(Value::Str(ere.to_string())));
+            asm.push(OpcodeL::Match);
+        }
+        // && と ||
+        // 左辺で結果が決まる場合は右辺を評価しない
+        //   left && right          left || right
+        //     left                   left
+        //     NIf and_decided        If or_decided
+        //     right                  right
+        //     NIf and_decided        If or_decided
+        //     Push 1                 Push 0
+        //     Jump and_end           Jump or_end
+        //   and_decided:           or_decided:
+        //     Push 0                 Push 1
+        //   and_end:               or_end:
+        ast::Expression::BinaryOp {
+            op: op @ (ast::BOperator::And | ast::BOperator::Or),
+            left,
+            right,
+        } => {
+            let label = env.logical_label_count;
+            env.logical_label_count += 1;
+
+            let is_and = matches!(op, ast::BOperator::And);
+            let (name, decided) = if is_and { ("and", 0.0) } else { ("or", 1.0) };
+            let decided_label = format!("{name}_decided_{label}");
+            let jump = |label: &str| {
+                if is_and {
+                    OpcodeL::NIf(label.to_string())
+                } else {
+                    OpcodeL::If(label.to_string())
+                }
+            };
             compile_expression(left, asm, env)?;
+            asm.push(jump(&decided_label));
             compile_expression(right, asm, env)?;
+            asm.push(jump(&decided_label));
+            asm.push(OpcodeL::Push(Value::Num(1.0 - decided)));
+            asm.push(OpcodeL::Jump(format!("{name}_end_{label}")));
+            asm.push(OpcodeL::Label(decided_label));
+            asm.push(OpcodeL::Push(Value::Num(decided)));
+            asm.push(OpcodeL::Label(format!("{name}_end_{label}")));
+        }
+        ast::Expression::BinaryOp { op, left, right } => {
+            compile_expression(left, asm, env)?;
+            match (op, right.as_ref()) {
+                // ~ の右辺の正規表現は$0とマッチさせずにそのままパターンとして使う
+                (ast::BOperator::Match | ast::BOperator::NotMatch, _) => {
+                    compile_regex_arg(right, asm, env)?
+                }
+                _ => compile_expression(right, asm, env)?,
+            }
             compile_operator(op, asm);
         }
+        ast::Expression::Unary { op, expr } => {
+            compile_expression(expr, asm, env)?;
+            asm.push(match op {
+                ast::UOperator::Minus => OpcodeL::Neg,
+                ast::UOperator::Plus => OpcodeL::Plus,
+                ast::UOperator::Not => OpcodeL::Not,
+            });
+        }
+        // 三項演算子
+        // 選ばれなかった方の式は評価しない
+        ast::Expression::Ternary { cond, then, els } => {
+            let label = env.if_label_count;
+            env.if_label_count += 1;
+
+            compile_expression(cond, asm, env)?;
+            asm.push(OpcodeL::NIf(format!("if_{label}")));
+            compile_expression(then, asm, env)?;
+            asm.push(OpcodeL::Jump(format!("if_elskip_{label}")));
+            asm.push(OpcodeL::Label(format!("if_{label}")));
+            compile_expression(els, asm, env)?;
+            asm.push(OpcodeL::Label(format!("if_elskip_{label}")));
+        }
+        // (key) in array
+        // 要素を作らずに存在するかを調べる
+        ast::Expression::In { expr_list, name } => {
+            compile_subscript(expr_list, asm, env)?;
+            asm.push(OpcodeL::InArray(name.to_string()));
+        }
+        // lvalueの添字(フィールドの番号)は一度だけ評価する
         ast::Expression::IncDec { op, lval } => {
-            // 下のLvalueと共通化
-            let loadlval = |lvalue: &ast::LValue,
-                            asm: &mut Asm,
-                            env: &mut CompileEnv|
-             -> Result<(), &'static str> {
-                match lvalue {
-                    ast::LValue::Name(name) => {
-                        if let Some(sfi) = env.func_args.iter().position(|n| n == name) {
-                            asm.push(OpcodeL::LoadSFVar(sfi));
-                        } else {
-                            // 関数の引数にない場合
-                            env.variables.insert(name.to_string());
-                            asm.push(OpcodeL::LoadVar(name.to_string()));
-                        }
-                    }
-                    ast::LValue::Array { name, expr_list } => {
-                        // 順番に注意
-                        for expr in expr_list.iter() {
-                            compile_expression(expr, asm, env)?;
-                        }
-                        asm.push(OpcodeL::LoadArray(name.to_string()));
-                    }
-                };
-                Ok(())
-            };
-            let assign = |lvalue: &ast::LValue,
-                          asm: &mut Asm,
-                          env: &mut CompileEnv|
-             -> Result<(), &'static str> {
-                match lvalue {
-                    ast::LValue::Name(name) => {
-                        if let Some(sfi) = env.func_args.iter().position(|n| n == name) {
-                            asm.push(OpcodeL::SetSFVar(sfi));
-                        } else {
-                            env.variables.insert(name.to_string());
-                            asm.push(OpcodeL::SetVar(name.to_string()))
-                        }
-                    }
-                    ast::LValue::Array { name, expr_list } => {
-                        for expr in expr_list.iter() {
-                            compile_expression(expr, asm, env)?;
-                        }
-                        asm.push(OpcodeL::SetArray(name.to_string()));
-                    }
-                }
-                Ok(())
-            };
+            let target = compile_lvalue_ref(lval, asm, env)?;
+            compile_load_ref(&target, asm);
+            asm.push(OpcodeL::Push(ast::Value::Num(1.0)));
+            match op {
+                ast::IncDecType::PreInc | ast::IncDecType::PostInc => asm.push(OpcodeL::Add),
+                ast::IncDecType::PreDec | ast::IncDecType::PostDec => asm.push(OpcodeL::Sub),
+            }
+            asm.push(OpcodeL::SetRef(target));
+            // TODO: 未初期化のときi++は0．無理矢理実装している
             match op {
-                ast::IncDecType::PreInc => {
-                    // increment lvalue
-                    loadlval(lval, asm, env)?;
-                    asm.push(OpcodeL::Push(ast::Value::Num(1.0)));
-                    asm.push(OpcodeL::Add);
-                    assign(lval, asm, env)?;
-                    // load lvalue
-                    loadlval(lval, asm, env)?;
-                }
-                ast::IncDecType::PreDec => {
-                    loadlval(lval, asm, env)?;
-                    asm.push(OpcodeL::Push(ast::Value::Num(1.0)));
-                    asm.push(OpcodeL::Sub);
-                    assign(lval, asm, env)?;
-                    loadlval(lval, asm, env)?;
-                }
                 ast::IncDecType::PostInc => {
-                    // TODO: 未初期化のときi++は0．無理矢理実装している
-                    // increment lvalue
-                    loadlval(lval, asm, env)?;
-                    asm.push(OpcodeL::Push(ast::Value::Num(1.0)));
-                    asm.push(OpcodeL::Add);
-                    assign(lval, asm, env)?;
-                    // load lvalue
-                    loadlval(lval, asm, env)?;
                     asm.push(OpcodeL::Push(ast::Value::Num(1.0)));
                     asm.push(OpcodeL::Sub);
                 }
                 ast::IncDecType::PostDec => {
-                    loadlval(lval, asm, env)?;
-                    asm.push(OpcodeL::Push(ast::Value::Num(1.0)));
-                    asm.push(OpcodeL::Sub);
-                    assign(lval, asm, env)?;
-                    loadlval(lval, asm, env)?;
                     asm.push(OpcodeL::Push(ast::Value::Num(1.0)));
                     asm.push(OpcodeL::Add);
                 }
+                _ => {}
             }
         }
         ast::Expression::GetField(e) => {
             compile_expression(e, asm, env)?;
             asm.push(OpcodeL::GetField);
         }
-        ast::Expression::LValue(lvalue) => match lvalue {
-            ast::LValue::Name(name) => {
-                if let Some(sfi) = env.func_args.iter().position(|n| n == name) {
-                    asm.push(OpcodeL::LoadSFVar(sfi));
-                } else {
-                    // 関数の引数にない場合
-                    env.variables.insert(name.to_string());
-                    asm.push(OpcodeL::LoadVar(name.to_string()));
-                }
-            }
-            ast::LValue::Array { name, expr_list } => {
-                // 順番に注意
-                for expr in expr_list.iter() {
-                    compile_expression(expr, asm, env)?;
-                }
-                asm.push(OpcodeL::LoadArray(name.to_string()));
-            }
-        },
+        ast::Expression::LValue(lvalue) => compile_load_lvalue(lvalue, asm, env)?,
         ast::Expression::Assign { lval, expr } => {
             // TODO: 引数の書き換え
             compile_expression(expr, asm, env)?;
-            match lval {
-                ast::LValue::Name(name) => {
-                    if let Some(sfi) = env.func_args.iter().position(|n| n == name) {
-                        asm.push(OpcodeL::SetSFVar(sfi));
-                    } else {
-                        env.variables.insert(name.to_string());
-                        asm.push(OpcodeL::SetVar(name.to_string()))
-                    }
+            compile_store_lvalue(lval, asm, env)?;
+            asm.push(OpcodeL::Push(Value::None));
+        }
+        // lval op= expr
+        // lvalueの添字(フィールドの番号)は一度だけ評価する
+        ast::Expression::OpAssign { op, lval, expr } => {
+            let target = compile_lvalue_ref(lval, asm, env)?;
+            compile_load_ref(&target, asm);
+            compile_expression(expr, asm, env)?;
+            compile_operator(op, asm);
+            asm.push(OpcodeL::SetRef(target));
+        }
+        // getline
+        // 変数に読み込む場合は，読み込みに失敗したときのために変数の今の値を積んでおく
+        ast::Expression::Getline { kind, src, lval } => match lval {
+            Some(lval) => {
+                let target = compile_lvalue_ref(lval, asm, env)?;
+                if let Some(src) = src {
+                    compile_expression(src, asm, env)?;
                 }
-                ast::LValue::Array { name, expr_list } => {
-                    for expr in expr_list.iter() {
-                        compile_expression(expr, asm, env)?;
-                    }
-                    asm.push(OpcodeL::SetArray(name.to_string()));
+                asm.push(OpcodeL::GetlineVar(kind.clone()));
+                asm.push(OpcodeL::SetIf(target));
+            }
+            None => {
+                if let Some(src) = src {
+                    compile_expression(src, asm, env)?;
                 }
+                asm.push(OpcodeL::Getline(kind.clone()));
             }
-            asm.push(OpcodeL::Push(Value::None));
-        }
+        },
         ast::Expression::CallIFunc { name, args } => {
-            for e in args.iter().rev() {
-                compile_expression(e, asm, env)?;
-            }
             let index = ifunc::get_index_from_name(name).unwrap();
-            if args.len() != ifunc::get_len_of_args(index) {
+            let (min, max) = ifunc::get_range_of_args(index);
+            if args.len() < min || max < args.len() {
                 return Err("Invalid arg len");
             }
-            // TODO
-            // ここで引数の個数はチェックしたい
-            asm.push(OpcodeL::Call(index));
+            match name.as_str() {
+                // sub(ere, repl[, in]) / gsub(ere, repl[, in])
+                // 置き換えがあった場合だけ結果をinに代入し，置き換えた個数を残す
+                // inの添字(フィールドの番号)は一度だけ評価する
+                "sub" | "gsub" => {
+                    let target = match args.get(2) {
+                        Some(ast::Expression::LValue(lval)) => lval.clone(),
+                        Some(ast::Expression::GetField(e)) => ast::LValue::Field(e.clone()),
+                        None => {
+                            ast::LValue::Field(Box::new(ast::Expression::Value(Value::Num(0.0))))
+                        }
+                        _ => return Err("sub/gsub third parameter is not a changeable object"),
+                    };
+                    let target = compile_lvalue_ref(&target, asm, env)?;
+                    compile_load_ref(&target, asm);
+                    compile_expression(&args[1], asm, env)?;
+                    compile_regex_arg(&args[0], asm, env)?;
+                    asm.push(OpcodeL::Call(index));
+                    asm.push(OpcodeL::SetIf(target));
+                }
+                // match(s, ere)
+                // RSTART, RLENGTH を設定し，RSTARTを残す
+                "match" => {
+                    compile_regex_arg(&args[1], asm, env)?;
+                    compile_expression(&args[0], asm, env)?;
+                    asm.push(OpcodeL::Call(index));
+                    compile_store_lvalue(&ast::LValue::Name("RSTART".to_string()), asm, env)?;
+                    compile_store_lvalue(&ast::LValue::Name("RLENGTH".to_string()), asm, env)?;
+                }
+                // split(s, a[, fs])
+                // 分割した結果を配列aに入れ，要素の個数を残す
+                "split" => {
+                    let array = match &args[1] {
+                        ast::Expression::LValue(ast::LValue::Name(array)) => array,
+                        _ => return Err("split: second argument is not an array"),
+                    };
+                    if let Some(fs) = args.get(2) {
+                        compile_regex_arg(fs, asm, env)?;
+                    }
+                    compile_expression(&args[0], asm, env)?;
+                    asm.push(OpcodeL::Push(ast::Value::Num(args.len() as f64)));
+                    // 第3引数が正規表現リテラルなら，一文字や " " でもEREとして分割する
+                    let is_regex = matches!(args.get(2), Some(ast::Expression::Regex(_)));
+                    asm.push(OpcodeL::Push(ast::Value::Num(if is_regex {
+                        1.0
+                    } else {
+                        0.0
+                    })));
+                    asm.push(OpcodeL::Call(index));
+                    asm.push(OpcodeL::FillArray(array.to_string()));
+                }
+                _ => {
+                    for e in args.iter().rev() {
+                        compile_expression(e, asm, env)?;
+                    }
+                    // 引数の個数が変わる関数には個数を渡す
+                    if min != max {
+                        asm.push(OpcodeL::Push(ast::Value::Num(args.len() as f64)));
+                    }
+                    asm.push(OpcodeL::Call(index));
+                }
+            }
         }
         ast::Expression::CallUserFunc { name, args } => {
             if *env.functions.get(name).unwrap() < args.len() {
@@ -622,6 +909,159 @@ fn compile_expression(
     Ok(())
 }
 
+// 正規表現を引数にとる内蔵関数の引数
+// /ere/ は$0とマッチさせずにそのままパターンとして使う
+fn compile_regex_arg(
+    arg: &ast::Expression,
+    asm: &mut Asm,
+    env: &mut CompileEnv,
+) -> Result<(), &'static str> {
+    match arg {
+        ast::Expression::Regex(ere) => asm.push(OpcodeL::Push(Value::Str(ere.to_string()))),
+        _ => compile_expression(arg, asm, env)?,
+    }
+    Ok(())
+}
+
+// 出力先を積み，次のprint/printfの出力先を切り替える
+fn compile_redirect(
+    redirect: &Option<ast::Redirect>,
+    asm: &mut Asm,
+    env: &mut CompileEnv,
+) -> Result<(), &'static str> {
+    if let Some(redirect) = redirect {
+        compile_expression(&redirect.dest, asm, env)?;
+        asm.push(OpcodeL::Redirect(redirect.kind.clone()));
+    }
+    Ok(())
+}
+
+// lvalueの値をスタックに積む
+fn compile_load_lvalue(
+    lvalue: &ast::LValue,
+    asm: &mut Asm,
+    env: &mut CompileEnv,
+) -> Result<(), &'static str> {
+    match lvalue {
+        ast::LValue::Name(name) => {
+            if let Some(sfi) = env.func_args.iter().position(|n| n == name) {
+                asm.push(OpcodeL::LoadSFVar(sfi));
+            } else if let Some(var) = SpecialVar::from_name(name) {
+                asm.push(OpcodeL::LoadSpecial(var));
+            } else {
+                // 関数の引数にない場合
+                env.variables.insert(name.to_string());
+                asm.push(OpcodeL::LoadVar(name.to_string()));
+            }
+        }
+        ast::LValue::Array { name, expr_list } => {
+            // 順番に注意
+            compile_subscript(expr_list, asm, env)?;
+            asm.push(OpcodeL::LoadArray(name.to_string()));
+        }
+        ast::LValue::Field(expr) => {
+            compile_expression(expr, asm, env)?;
+            asm.push(OpcodeL::GetField);
+        }
+    };
+    Ok(())
+}
+
+// スタックのトップの値をlvalueに代入する
+fn compile_store_lvalue(
+    lvalue: &ast::LValue,
+    asm: &mut Asm,
+    env: &mut CompileEnv,
+) -> Result<(), &'static str> {
+    match lvalue {
+        ast::LValue::Name(name) => {
+            if let Some(sfi) = env.func_args.iter().position(|n| n == name) {
+                asm.push(OpcodeL::SetSFVar(sfi));
+            } else if let Some(var) = SpecialVar::from_name(name) {
+                asm.push(OpcodeL::SetSpecial(var));
+            } else {
+                env.variables.insert(name.to_string());
+                asm.push(OpcodeL::SetVar(name.to_string()))
+            }
+        }
+        ast::LValue::Array { name, expr_list } => {
+            compile_subscript(expr_list, asm, env)?;
+            asm.push(OpcodeL::SetArray(name.to_string()));
+        }
+        ast::LValue::Field(expr) => {
+            compile_expression(expr, asm, env)?;
+            asm.push(OpcodeL::SetField);
+        }
+    }
+    Ok(())
+}
+
+// lvalueの添字(フィールドの番号)だけを評価してスタックに積み，SetIfの代入先を返す
+// 添字の式に副作用があっても一度しか評価しないようにするため
+fn compile_lvalue_ref(
+    lvalue: &ast::LValue,
+    asm: &mut Asm,
+    env: &mut CompileEnv,
+) -> Result<SetTargetL, &'static str> {
+    Ok(match lvalue {
+        ast::LValue::Name(name) => {
+            if let Some(sfi) = env.func_args.iter().position(|n| n == name) {
+                SetTargetL::SFVar(sfi)
+            } else if let Some(var) = SpecialVar::from_name(name) {
+                SetTargetL::Special(var)
+            } else {
+                env.variables.insert(name.to_string());
+                SetTargetL::Var(name.to_string())
+            }
+        }
+        ast::LValue::Array { name, expr_list } => {
+            compile_subscript(expr_list, asm, env)?;
+            SetTargetL::Array(name.to_string())
+        }
+        ast::LValue::Field(expr) => {
+            compile_expression(expr, asm, env)?;
+            SetTargetL::Field
+        }
+    })
+}
+
+// compile_lvalue_refで積んだ添字(フィールドの番号)を残したまま，今の値を積む
+fn compile_load_ref(target: &SetTargetL, asm: &mut Asm) {
+    match target {
+        SetTargetL::Var(name) => asm.push(OpcodeL::LoadVar(name.to_string())),
+        SetTargetL::SFVar(n) => asm.push(OpcodeL::LoadSFVar(*n)),
+        SetTargetL::Special(var) => asm.push(OpcodeL::LoadSpecial(*var)),
+        SetTargetL::Array(name) => {
+            asm.push(OpcodeL::Dup);
+            asm.push(OpcodeL::LoadArray(name.to_string()));
+        }
+        SetTargetL::Field => {
+            asm.push(OpcodeL::Dup);
+            asm.push(OpcodeL::GetField);
+        }
+    }
+}
+
+// 配列の添字をスタックに積む
+// 添字が複数ある場合 a[i, j] はSUBSEPで連結して一つの文字列にする
+fn compile_subscript(
+    expr_list: &[ast::Expression],
+    asm: &mut Asm,
+    env: &mut CompileEnv,
+) -> Result<(), &'static str> {
+    for (i, expr) in expr_list.iter().enumerate() {
+        compile_expression(expr, asm, env)?;
+        if i > 0 {
+            asm.push(OpcodeL::Cat);
+        }
+        if i < expr_list.len() - 1 {
+            compile_load_lvalue(&ast::LValue::Name("SUBSEP".to_string()), asm, env)?;
+            asm.push(OpcodeL::Cat);
+        }
+    }
+    Ok(())
+}
+
 fn compile_operator(op: &ast::BOperator, asm: &mut Asm) {
     asm.push(match op {
         ast::BOperator::Add => OpcodeL::Add,
@@ -631,14 +1071,16 @@ fn compile_operator(op: &ast::BOperator, asm: &mut Asm) {
         ast::BOperator::Pow => OpcodeL::Pow,
         ast::BOperator::Mod => OpcodeL::Mod,
         ast::BOperator::Cat => OpcodeL::Cat,
-        ast::BOperator::And => OpcodeL::And,
-        ast::BOperator::Or => OpcodeL::Or,
+        // 短絡評価するのでcompile_expressionで処理する
+        ast::BOperator::And | ast::BOperator::Or => unreachable!(),
         ast::BOperator::LessThan => OpcodeL::LessThan,
         ast::BOperator::LessEqualThan => OpcodeL::LessEqualThan,
         ast::BOperator::NotEqual => OpcodeL::NotEqual,
         ast::BOperator::Equal => OpcodeL::Equal,
         ast::BOperator::GreaterThan => OpcodeL::GreaterThan,
         ast::BOperator::GreaterEqualThan => OpcodeL::GreaterEqualThan,
+        ast::BOperator::Match => OpcodeL::Match,
+        ast::BOperator::NotMatch => OpcodeL::NotMatch,
     })
 }
 
@@ -647,39 +1089,64 @@ fn asm_to_vmprogram(asm: &Asm, _env: &mut CompileEnv) -> VMProgram {
 
     // 変数名の解決
     let mut names: HashMap<String, usize> = HashMap::new();
-    let mut arraynames: HashMap<String, usize> = HashMap::new();
+    // 組み込みの配列は先頭に固定で割り当てる
+    let mut arraynames: HashMap<String, usize> = BUILTIN_ARRAYS
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.to_string(), i))
+        .collect();
     // 全ての変数名を探索
     for i in a.iter() {
         if let OpcodeL::SetVar(name) = i {
-            if names.get(name).is_none() {
+            if !names.contains_key(name) {
                 names.insert(name.to_string(), names.len());
             }
         }
-        if let OpcodeL::LoadVar(name) = i {
-            if names.get(name).is_none() {
+        if let OpcodeL::LoadVar(name)
+        | OpcodeL::SetIf(SetTargetL::Var(name))
+        | OpcodeL::SetRef(SetTargetL::Var(name)) = i
+        {
+            if !names.contains_key(name) {
                 names.insert(name.to_string(), names.len());
             }
         }
-        if let OpcodeL::LoadArray(name) = i {
-            if arraynames.get(name).is_none() {
-                arraynames.insert(name.to_string(), arraynames.len());
-            }
-        }
-        if let OpcodeL::SetArray(name) = i {
-            if arraynames.get(name).is_none() {
+        if let OpcodeL::LoadArray(name)
+        | OpcodeL::SetArray(name)
+        | OpcodeL::InArray(name)
+        | OpcodeL::DeleteArray(name)
+        | OpcodeL::ClearArray(name)
+        | OpcodeL::FillArray(name)
+        | OpcodeL::IterInit(name)
+        | OpcodeL::SetIf(SetTargetL::Array(name))
+        | OpcodeL::SetRef(SetTargetL::Array(name)) = i
+        {
+            if !arraynames.contains_key(name) {
                 arraynames.insert(name.to_string(), arraynames.len());
             }
         }
     }
 
     // 変数分の領域を確保
-    if !arraynames.is_empty() {
+    // (変数名はコマンドラインの var=value で使う)
+    if arraynames.len() > BUILTIN_ARRAYS.len() {
         a.insert(0, OpcodeL::InitEnvArray(arraynames.len()));
     }
     if !names.is_empty() {
-        a.insert(0, OpcodeL::InitEnv(names.len()));
+        let mut list = vec![String::new(); names.len()];
+        for (name, i) in names.iter() {
+            list[*i] = name.to_string();
+        }
+        a.insert(0, OpcodeL::InitEnv(list));
     }
 
+    let set_target = |target: &SetTargetL| match target {
+        SetTargetL::Var(n) => SetTarget::Var(*names.get(n).unwrap()),
+        SetTargetL::SFVar(n) => SetTarget::SFVar(*n),
+        SetTargetL::Special(var) => SetTarget::Special(*var),
+        SetTargetL::Array(n) => SetTarget::Array(*arraynames.get(n).unwrap()),
+        SetTargetL::Field => SetTarget::Field,
+    };
+
     // ラベル名の解決
     // 初めに全てのラベル位置を特定してジャンプ先の要素番号を特定する
     // これ以降アセンブリに追加，削除してはいけない
@@ -707,6 +1174,7 @@ fn asm_to_vmprogram(asm: &Asm, _env: &mut CompileEnv) -> VMProgram {
             OpcodeL::End => Opcode::End,
             OpcodeL::Push(value) => Opcode::Push(value.clone()),
             OpcodeL::Pop => Opcode::Pop,
+            OpcodeL::Dup => Opcode::Dup,
             // TODO
             OpcodeL::Jump(label) => Opcode::Jump(*labels.get(label).unwrap()),
             OpcodeL::If(label) => Opcode::If(*labels.get(label).unwrap()),
@@ -714,6 +1182,8 @@ fn asm_to_vmprogram(asm: &Asm, _env: &mut CompileEnv) -> VMProgram {
             OpcodeL::Call(i) => Opcode::Call(*i),
             OpcodeL::CallUserFunc(label) => Opcode::CallUserFunc(*labels.get(label).unwrap()),
             OpcodeL::Return => Opcode::Return,
+            OpcodeL::ExitCode => Opcode::ExitCode,
+            OpcodeL::Exit(label) => Opcode::Exit(*labels.get(label).unwrap()),
             // Expression
             OpcodeL::Add => Opcode::Add,
             OpcodeL::Sub => Opcode::Sub,
@@ -721,29 +1191,50 @@ fn asm_to_vmprogram(asm: &Asm, _env: &mut CompileEnv) -> VMProgram {
             OpcodeL::Div => Opcode::Div,
             OpcodeL::Pow => Opcode::Pow,
             OpcodeL::Mod => Opcode::Mod,
+            OpcodeL::Neg => Opcode::Neg,
+            OpcodeL::Plus => Opcode::Plus,
+            OpcodeL::Not => Opcode::Not,
             OpcodeL::Cat => Opcode::Cat,
-            OpcodeL::And => Opcode::And,
-            OpcodeL::Or => Opcode::Or,
             OpcodeL::LessThan => Opcode::LessThan,
             OpcodeL::LessEqualThan => Opcode::LessEqualThan,
             OpcodeL::NotEqual => Opcode::NotEqual,
             OpcodeL::Equal => Opcode::Equal,
             OpcodeL::GreaterThan => Opcode::GreaterThan,
             OpcodeL::GreaterEqualThan => Opcode::GreaterEqualThan,
+            OpcodeL::Match => Opcode::Match,
+            OpcodeL::NotMatch => Opcode::NotMatch,
             // AWK
             OpcodeL::Readline => Opcode::Readline,
+            OpcodeL::NextFile => Opcode::NextFile,
+            OpcodeL::Getline(kind) => Opcode::Getline(kind.clone()),
+            OpcodeL::GetlineVar(kind) => Opcode::GetlineVar(kind.clone()),
             OpcodeL::Print(len) => Opcode::Print(*len),
             OpcodeL::Printf(len) => Opcode::Printf(*len),
+            OpcodeL::Redirect(kind) => Opcode::Redirect(kind.clone()),
             OpcodeL::GetField => Opcode::GetField,
+            OpcodeL::SetField => Opcode::SetField,
+            OpcodeL::LoadRange(i) => Opcode::LoadRange(*i),
+            OpcodeL::SetRange(i, b) => Opcode::SetRange(*i, *b),
             // Variable
-            OpcodeL::InitEnv(n) => Opcode::InitEnv(*n),
+            OpcodeL::InitEnv(n) => Opcode::InitEnv(n.clone()),
             OpcodeL::InitEnvArray(n) => Opcode::InitEnvArray(*n),
             OpcodeL::LoadVar(n) => Opcode::LoadVar(*names.get(n).unwrap()),
             OpcodeL::SetArray(n) => Opcode::SetArray(*arraynames.get(n).unwrap()),
             OpcodeL::SetVar(n) => Opcode::SetVar(*names.get(n).unwrap()),
             OpcodeL::LoadArray(n) => Opcode::LoadArray(*arraynames.get(n).unwrap()),
+            OpcodeL::InArray(n) => Opcode::InArray(*arraynames.get(n).unwrap()),
+            OpcodeL::DeleteArray(n) => Opcode::DeleteArray(*arraynames.get(n).unwrap()),
+            OpcodeL::ClearArray(n) => Opcode::ClearArray(*arraynames.get(n).unwrap()),
+            OpcodeL::FillArray(n) => Opcode::FillArray(*arraynames.get(n).unwrap()),
+            OpcodeL::IterInit(n) => Opcode::IterInit(*arraynames.get(n).unwrap()),
+            OpcodeL::IterNext(label) => Opcode::IterNext(*labels.get(label).unwrap()),
+            OpcodeL::IterEnd => Opcode::IterEnd,
             OpcodeL::LoadSFVar(n) => Opcode::LoadSFVar(*n),
             OpcodeL::SetSFVar(n) => Opcode::SetSFVar(*n),
+            OpcodeL::LoadSpecial(var) => Opcode::LoadSpecial(*var),
+            OpcodeL::SetSpecial(var) => Opcode::SetSpecial(*var),
+            OpcodeL::SetIf(target) => Opcode::SetIf(set_target(target)),
+            OpcodeL::SetRef(target) => Opcode::SetRef(set_target(target)),
             // ジャンプ先を示す
             OpcodeL::Label(_label) => unreachable!(),
         })
@@ -755,10 +1246,13 @@ fn asm_to_vmprogram(asm: &Asm, _env: &mut CompileEnv) -> VMProgram {
 fn test_compile() {
     let ast = vec![ast::Item::PatternAction(ast::PatternAction {
         pattern: ast::Pattern::Begin,
-        action: ast::Statement::Action(vec![ast::Statement::Print(vec![
-            ast::Expression::Value(ast::Value::Num(1.0)),
-            ast::Expression::Value(ast::Value::Num(2.0)),
-        ])]),
+        action: ast::Statement::Action(vec![ast::Statement::Print {
+            args: vec![
+                ast::Expression::Value(ast::Value::Num(1.0)),
+                ast::Expression::Value(ast::Value::Num(2.0)),
+            ],
+            redirect: None,
+        }]),
     })];
     let expect = vec![
         Opcode::Push(ast::Value::Num(1.0)),
@@ -775,13 +1269,14 @@ fn test_compile() {
 fn test_compile2() {
     let ast = vec![ast::Item::PatternAction(ast::PatternAction {
         pattern: ast::Pattern::Begin,
-        action: ast::Statement::Action(vec![ast::Statement::Print(vec![
-            ast::Expression::BinaryOp {
+        action: ast::Statement::Action(vec![ast::Statement::Print {
+            args: vec![ast::Expression::BinaryOp {
                 op: ast::BOperator::Div,
                 left: Box::new(ast::Expression::Value(ast::Value::Num(6.0))),
                 right: Box::new(ast::Expression::Value(ast::Value::Num(2.0))),
-            },
-        ])]),
+            }],
+            redirect: None,
+        }]),
     })];
     let expect = vec![
         Opcode::Push(ast::Value::Num(6.0)),
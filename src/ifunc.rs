@@ -18,89 +18,149 @@ use crate::vm::VM;
 // system(expression)
 
 type Func = fn(vm: &mut VM);
+// 引数の個数がminargからmaxargまでの範囲で変わる関数は，
+// 引数の後に引数の個数がスタックに積まれて呼び出される
 struct IFunc {
     name: &'static str,
     func: Func,
-    arglen: usize,
+    minarg: usize,
+    maxarg: usize,
 }
 
 const INTERNAL_FUNC: &[IFunc] = &[
     IFunc {
         name: "sin",
         func: ifunc::ifunc_sin,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
     },
     IFunc {
         name: "cos",
         func: ifunc::ifunc_cos,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
     },
     IFunc {
         name: "exp",
         func: ifunc::ifunc_exp,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
     },
     IFunc {
         name: "tolower",
         func: ifunc::ifunc_tolower,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
     },
     IFunc {
         name: "toupper",
         func: ifunc::ifunc_toupper,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
     },
     IFunc {
         name: "rand",
         func: ifunc::ifunc_rand,
-        arglen: 0,
+        minarg: 0,
+        maxarg: 0,
     },
     IFunc {
         name: "sqrt",
         func: ifunc::ifunc_sqrt,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
     },
     IFunc {
         name: "log",
         func: ifunc::ifunc_log,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
     },
     IFunc {
         name: "int",
         func: ifunc::ifunc_int,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
     },
     // 引数をオプショナルに
     IFunc {
         name: "srand",
         func: ifunc::ifunc_srand,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
     },
     IFunc {
         name: "atan2",
         func: ifunc::ifunc_atan2,
-        arglen: 2,
+        minarg: 2,
+        maxarg: 2,
     },
     // 引数をオプショナルに
     IFunc {
         name: "length",
         func: ifunc::ifunc_length,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
     },
     IFunc {
         name: "index",
         func: ifunc::ifunc_index,
-        arglen: 2,
+        minarg: 2,
+        maxarg: 2,
     },
     IFunc {
         name: "system",
         func: ifunc::ifunc_system,
-        arglen: 1,
+        minarg: 1,
+        maxarg: 1,
+    },
+    IFunc {
+        name: "substr",
+        func: ifunc::ifunc_substr,
+        minarg: 2,
+        maxarg: 3,
+    },
+    IFunc {
+        name: "split",
+        func: ifunc::ifunc_split,
+        minarg: 2,
+        maxarg: 3,
+    },
+    IFunc {
+        name: "sub",
+        func: ifunc::ifunc_sub,
+        minarg: 2,
+        maxarg: 3,
+    },
+    IFunc {
+        name: "gsub",
+        func: ifunc::ifunc_gsub,
+        minarg: 2,
+        maxarg: 3,
+    },
+    IFunc {
+        name: "match",
+        func: ifunc::ifunc_match,
+        minarg: 2,
+        maxarg: 2,
+    },
+    IFunc {
+        name: "sprintf",
+        func: ifunc::ifunc_sprintf,
+        minarg: 1,
+        maxarg: usize::MAX,
+    },
+    IFunc {
+        name: "close",
+        func: ifunc::ifunc_close,
+        minarg: 1,
+        maxarg: 1,
     },
     IFunc {
         name: "flush",
         func: ifunc::ifunc_flush,
-        arglen: 0,
+        minarg: 0,
+        maxarg: 0,
     },
 ];
 
@@ -112,8 +172,9 @@ pub fn call_internal_func_from_index(index: usize, vm: &mut VM) {
     (INTERNAL_FUNC[index].func)(vm);
 }
 
-pub fn get_len_of_args(index: usize) -> usize {
-    INTERNAL_FUNC[index].arglen
+// 引数の個数の範囲 (最小, 最大)
+pub fn get_range_of_args(index: usize) -> (usize, usize) {
+    (INTERNAL_FUNC[index].minarg, INTERNAL_FUNC[index].maxarg)
 }
 
 #[test]
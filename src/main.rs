@@ -12,9 +12,14 @@ mod vm;
 /*
  * AWK オプション
  *   -h            : ヘルプを表示して終了
- *   -f progfile   : progfileを実行
+ *   --version     : バージョンを表示して終了
+ *   -f progfile   : progfileを実行 (複数指定するとつなげて一つのプログラムにする)
+ *   -F fs         : フィールドセパレータ
+ *   -v var=value  : BEGINの前に変数に代入する (複数指定可)
  *   -d 1|2|3      : デバッグレベル
+ *   --            : オプションの終わり
  *   'program'     : programを実行
+ *   file ...      : 入力ファイル (- は標準入力，var=value は変数への代入)
  */
 
 // 速度計測
@@ -41,16 +46,21 @@ fn main() {
     };
 
     let mut opts = Options::new();
+    // プログラムより後ろの引数はオプションとして扱わない
+    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
     opts.optflag("h", "help", "Print this help menu");
+    opts.optflag("", "version", "Print version");
     opts.optopt("d", "", "Set debug level", "DEBUGLEVEL");
-    opts.optopt("f", "", "filename to run", "progfile");
+    opts.optmulti("f", "", "filename to run", "progfile");
+    opts.optopt("F", "", "field separator", "fs");
+    opts.optmulti("v", "", "assign value to var before BEGIN", "var=value");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(e) => {
             eprintln!("Error: {}", e);
             print_usage(&option.program_name);
-            return;
+            std::process::exit(2);
         }
     };
 
@@ -60,6 +70,26 @@ fn main() {
         return;
     };
 
+    if matches.opt_present("version") {
+        println!("kawk {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    // -v の代入を確認しておく
+    let mut assignments = vec![];
+    for arg in matches.opt_strs("v") {
+        match vm::split_assignment(&arg) {
+            Some((name, value)) => assignments.push((name.to_string(), parser::unescape(value))),
+            None => {
+                eprintln!(
+                    "{}: fatal: `{}' is not a legal variable assignment",
+                    option.program_name, arg
+                );
+                std::process::exit(2);
+            }
+        }
+    }
+
     if let Some(debuglevel) = matches.opt_str("d") {
         println!("DEBUGLEVEL: {}", debuglevel);
         option.debuglevel = if debuglevel == "1" {
@@ -70,40 +100,47 @@ fn main() {
             DebugLevel::Env
         } else {
             eprintln!("Invalid debuglevel: {}", debuglevel);
-            return;
+            std::process::exit(2);
         }
     }
 
-    let program = if let Some(filename) = matches.opt_str("f") {
-        let mut f = match File::open(&filename) {
-            Ok(f) => f,
-            Err(_) => {
-                eprintln!("{}: fatal: cannot open source file `{}' for reading: No such file or directory", &args[0], filename);
-                return;
-            }
-        };
+    // プログラムより後ろの引数 (入力ファイルなど)
+    let mut operands = matches.free.clone();
+    let progfiles = matches.opt_strs("f");
+    let program = if !progfiles.is_empty() {
+        // 複数のprogfileは改行を挟んでつなげる
+        let mut program = String::new();
+        for filename in progfiles {
+            let mut f = match File::open(&filename) {
+                Ok(f) => f,
+                Err(_) => {
+                    eprintln!("{}: fatal: cannot open source file `{}' for reading: No such file or directory", &args[0], filename);
+                    std::process::exit(2);
+                }
+            };
 
-        let mut contents = String::new();
-        match f.read_to_string(&mut contents) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!(
-                    "{}: fatal: cannot read source file `{}': {}",
-                    option.program_name, filename, err
-                );
-                return;
-            }
-        };
-        contents
+            let mut contents = String::new();
+            match f.read_to_string(&mut contents) {
+                Ok(_) => (),
+                Err(err) => {
+                    eprintln!(
+                        "{}: fatal: cannot read source file `{}': {}",
+                        option.program_name, filename, err
+                    );
+                    std::process::exit(2);
+                }
+            };
+            program.push_str(&contents);
+            program.push('\n');
+        }
+        program
     } else {
-        match matches.free.first() {
-            Some(program) => program.clone(),
-            None => {
-                // 実行するプログラムがなければメッセージを表示
-                print_usage(&args[0]);
-                return;
-            }
+        if operands.is_empty() {
+            // 実行するプログラムがなければメッセージを表示
+            print_usage(&args[0]);
+            std::process::exit(2);
         }
+        operands.remove(0)
     };
 
     // Parse
@@ -142,24 +179,49 @@ fn main() {
     let mut r = std::io::stdin().lock();
     let mut w = std::io::stdout().lock();
     let mut vm = vm::VM::new(&vmprg);
-    vm.run(&mut r, &mut w);
+    vm.set_args(&operands);
+    if let Some(fs) = matches.opt_str("F") {
+        // -Ft はタブ
+        let fs = if fs == "t" {
+            "\t".to_string()
+        } else {
+            parser::unescape(&fs)
+        };
+        vm.assign("FS", ast::Value::Str(fs));
+    }
+    for (name, value) in assignments {
+        vm.assign(&name, ast::Value::StrNum(value));
+    }
+    let status = vm.run(&mut r, &mut w);
 
     if option.debuglevel == DebugLevel::Env {
         vm.show_stack_and_env();
     }
+
+    w.flush().unwrap();
+    std::process::exit(status);
 }
 
 fn print_usage(binary_name: &str) {
-    println!("usage: {} 'prog'", binary_name);
+    eprintln!(
+        "usage: {} [-F fs] [-v var=value] [-f progfile | 'prog'] [file ...]",
+        binary_name
+    );
 }
 
 fn print_help(binary_name: &str) {
     println!(
         indoc! {"
-            Usage: {} [options] 
+            Usage: {} [options] 'prog' [file ...]
             options:
                     -f progfile
                         file to run
+                    -F fs
+                        field separator
+                    -v var=value
+                        assign value to var before BEGIN
+                    --version
+                        print version
                     -d 1|2|3
                         specify debug level
         "},
@@ -173,12 +235,15 @@ fn show_vmprog(vmprog: &compile::VMProgram) {
             vm::Opcode::End => "end",
             vm::Opcode::Push(_) => "push",
             vm::Opcode::Pop => "pop",
+            vm::Opcode::Dup => "dup",
             vm::Opcode::Jump(_) => "jump",
             vm::Opcode::If(_) => "if",
             vm::Opcode::NIf(_) => "nif",
             vm::Opcode::Call(_) => "call",
             vm::Opcode::CallUserFunc(_) => "calluserfunc",
             vm::Opcode::Return => "return",
+            vm::Opcode::ExitCode => "exitcode",
+            vm::Opcode::Exit(_) => "exit",
             // Expression
             vm::Opcode::Add => "add",
             vm::Opcode::Sub => "sub",
@@ -186,20 +251,30 @@ fn show_vmprog(vmprog: &compile::VMProgram) {
             vm::Opcode::Div => "div",
             vm::Opcode::Pow => "pow",
             vm::Opcode::Mod => "mod",
+            vm::Opcode::Neg => "neg",
+            vm::Opcode::Plus => "plus",
+            vm::Opcode::Not => "not",
             vm::Opcode::Cat => "cat",
-            vm::Opcode::And => "and",
-            vm::Opcode::Or => "or",
             vm::Opcode::LessThan => "lessthan",
             vm::Opcode::LessEqualThan => "lessequalthan",
             vm::Opcode::NotEqual => "notequal",
             vm::Opcode::Equal => "equal",
             vm::Opcode::GreaterThan => "greaterthan",
             vm::Opcode::GreaterEqualThan => "greaterequalthan",
+            vm::Opcode::Match => "match",
+            vm::Opcode::NotMatch => "notmatch",
             // AWK
             vm::Opcode::Readline => "readline",
+            vm::Opcode::NextFile => "nextfile",
+            vm::Opcode::Getline(_) => "getline",
+            vm::Opcode::GetlineVar(_) => "getlinevar",
             vm::Opcode::Print(_) => "print",
             vm::Opcode::Printf(_) => "printf",
+            vm::Opcode::Redirect(_) => "redirect",
             vm::Opcode::GetField => "getfield",
+            vm::Opcode::SetField => "setfield",
+            vm::Opcode::LoadRange(_) => "loadrange",
+            vm::Opcode::SetRange(_, _) => "setrange",
             // Variable
             vm::Opcode::InitEnv(_) => "initenv",
             vm::Opcode::InitEnvArray(_) => "initenvarray",
@@ -207,8 +282,19 @@ fn show_vmprog(vmprog: &compile::VMProgram) {
             vm::Opcode::SetVar(_) => "setval",
             vm::Opcode::LoadArray(_) => "loadarray",
             vm::Opcode::SetArray(_) => "setarray",
+            vm::Opcode::InArray(_) => "inarray",
+            vm::Opcode::DeleteArray(_) => "deletearray",
+            vm::Opcode::ClearArray(_) => "cleararray",
+            vm::Opcode::FillArray(_) => "fillarray",
+            vm::Opcode::IterInit(_) => "iterinit",
+            vm::Opcode::IterNext(_) => "iternext",
+            vm::Opcode::IterEnd => "iterend",
             vm::Opcode::LoadSFVar(_) => "loadsfvar",
             vm::Opcode::SetSFVar(_) => "setsfvar",
+            vm::Opcode::LoadSpecial(_) => "loadspecial",
+            vm::Opcode::SetSpecial(_) => "setspecial",
+            vm::Opcode::SetIf(_) => "setif",
+            vm::Opcode::SetRef(_) => "setref",
         };
 
         let arg = match opcode {
@@ -216,19 +302,34 @@ fn show_vmprog(vmprog: &compile::VMProgram) {
             vm::Opcode::Jump(i) => i.to_string(),
             vm::Opcode::If(i) => i.to_string(),
             vm::Opcode::NIf(i) => i.to_string(),
+            vm::Opcode::Exit(i) => i.to_string(),
+            vm::Opcode::Getline(kind) => format!("{:?}", kind),
+            vm::Opcode::GetlineVar(kind) => format!("{:?}", kind),
             // 内蔵関数と対応させたい
             vm::Opcode::Call(i) => i.to_string(),
             vm::Opcode::CallUserFunc(i) => i.to_string(),
             vm::Opcode::Print(l) => l.to_string(),
             vm::Opcode::Printf(l) => l.to_string(),
-            vm::Opcode::InitEnv(n) => n.to_string(),
+            vm::Opcode::Redirect(kind) => format!("{:?}", kind),
+            vm::Opcode::InitEnv(names) => names.join(" "),
             vm::Opcode::InitEnvArray(n) => n.to_string(),
             vm::Opcode::LoadVar(n) => n.to_string(),
             vm::Opcode::SetVar(n) => n.to_string(),
             vm::Opcode::LoadArray(n) => n.to_string(),
             vm::Opcode::SetArray(n) => n.to_string(),
+            vm::Opcode::InArray(n) => n.to_string(),
+            vm::Opcode::DeleteArray(n) => n.to_string(),
+            vm::Opcode::ClearArray(n) => n.to_string(),
+            vm::Opcode::FillArray(n) => n.to_string(),
+            vm::Opcode::IterInit(n) => n.to_string(),
+            vm::Opcode::IterNext(i) => i.to_string(),
             vm::Opcode::LoadSFVar(n) => n.to_string(),
             vm::Opcode::SetSFVar(n) => n.to_string(),
+            vm::Opcode::LoadSpecial(var) => format!("{:?}", var),
+            vm::Opcode::SetSpecial(var) => format!("{:?}", var),
+            vm::Opcode::SetIf(target) | vm::Opcode::SetRef(target) => format!("{:?}", target),
+            vm::Opcode::LoadRange(n) => n.to_string(),
+            vm::Opcode::SetRange(n, b) => format!("{n} {b}"),
             _ => "".to_string(),
         };
 
@@ -5,6 +5,11 @@ pub fn parse(prog: &str) -> Result<Vec<ast::Item>, peg::error::ParseError<peg::s
     awk::prog(prog)
 }
 
+/// コマンドライン引数 (-F fs など) のエスケープシーケンスを解釈する
+pub fn unescape(s: &str) -> String {
+    awk::escaped(s).unwrap_or_else(|_| s.to_string())
+}
+
 peg::parser! {
     pub grammar awk() for str {
         // BEGIN { print(123) } のような一連のプログラム
@@ -18,6 +23,16 @@ peg::parser! {
         // patternactionはpattern BEGIN とaction {} の複合
         rule patternaction() -> ast::Item
             = pattern:pattern() _ action:action() { ast::Item::PatternAction(ast::PatternAction { pattern, action }) }
+            // actionを省略した場合は { print } と同じ
+            / pattern:expression_pattern() {
+                ast::Item::PatternAction(ast::PatternAction {
+                    pattern,
+                    action: ast::Statement::Action(vec![ast::Statement::Print {
+                        args: vec![],
+                        redirect: None,
+                    }]),
+                })
+            }
 
         rule function() -> ast::Item
             // NOTE:: 内蔵関数の書き換えはどうする？
@@ -33,10 +48,15 @@ peg::parser! {
             = precedence! {
                 "BEGIN" { ast::Pattern::Begin }
                 "END" { ast::Pattern::End }
-                e:expression() { ast::Pattern::Expression(e) }
+                p:expression_pattern() { p }
                 "" { ast::Pattern::Always }
             }
 
+        // 条件式 / 範囲パターン /start/, /end/
+        rule expression_pattern() -> ast::Pattern
+            = s:expression() _ "," __ e:expression() { ast::Pattern::Range(s, e) }
+            / e:expression() { ast::Pattern::Expression(e) }
+
         // action は {} で囲われていて，それぞれの文はひとつ以上の ; で区切られている
         rule action() -> ast::Statement
             = "{" sp()? a:(statement() ** sp()) sp()? "}" { ast::Statement::Action(a) }
@@ -47,38 +67,56 @@ peg::parser! {
                 // 式
                 e:expression() { ast::Statement::Expression(e) }
                 // printf文
-                "printf" _ "(" _ fmt:expression() _ ")" {
+                "printf" _ "(" _ fmt:expression() a:(_ "," _ e:expression() { e })* _ ")" &redirect_end() r:redirect()? {
                     ast::Statement::Printf {
                         fmt: Box::new(fmt),
-                        args: vec![]
+                        args: a,
+                        redirect: r,
                     }
                 }
-                "printf" [' ' | '\t'] _ fmt:expression() {
+                "printf" [' ' | '\t'] _ fmt:print_expression() a:(_ "," _ e:print_expression() { e })* r:redirect()? {
                     ast::Statement::Printf {
                         fmt: Box::new(fmt),
-                        args: vec![]
+                        args: a,
+                        redirect: r,
                     }
                 }
                 // print文の引数の空白
                 // 括弧ありprint文
-                "print" _ "(" _ a:(expression() ** (_ "," _)) _ ")" {
-                    ast::Statement::Print(a)
+                // print (1), (2) のように括弧の後に式が続く場合は括弧なしprint文
+                "print" _ "(" _ a:(expression() ** (_ "," _)) _ ")" &redirect_end() r:redirect()? {
+                    ast::Statement::Print { args: a, redirect: r }
                 }
                 // 括弧なしprint文
-                "print" [' ' | '\t'] _ a:(expression() ** (_ "," _)) {
-                    ast::Statement::Print(a)
+                "print" [' ' | '\t'] _ a:(print_expression() ** (_ "," _)) r:redirect()? {
+                    ast::Statement::Print { args: a, redirect: r }
                 }
                 // 引数なし括弧なしprint文
-                "print" {
-                    ast::Statement::Print(vec![])
+                "print" r:redirect()? {
+                    ast::Statement::Print { args: vec![], redirect: r }
                 }
                 // while文
-                "while" _ "(" e:expression() _ ")" __ s:statement() {
+                "while" _ "(" _ e:expression() _ ")" __ s:statement() {
                     ast::Statement::While {
                         exp: e,
                         stat: Box::new(s)
                     }
                 }
+                // do-while文
+                "do" kw_end() __ s:statement() (_ ";")? __ "while" _ "(" _ e:expression() _ ")" {
+                    ast::Statement::DoWhile {
+                        stat: Box::new(s),
+                        exp: e
+                    }
+                }
+                // for (key in array)
+                "for" _ "(" _ v:name() _ "in" kw_end() _ n:name() _ ")" __ s:statement() {
+                    ast::Statement::ForIn {
+                        var: v,
+                        name: n,
+                        stat: Box::new(s)
+                    }
+                }
                 // for
                 // statement()の代わりにsimple_statement()をつくる
                 "for" _ "(" _ a:statement() _ ";" _ b:expression() _ ";" _ c:statement() _ ")" __ s:statement() {
@@ -114,79 +152,75 @@ peg::parser! {
                 a:action() { a }
                 "break" { ast::Statement::Break }
                 "continue" { ast::Statement::Continue }
+                "exit" kw_end() _ e:expression() { ast::Statement::Exit(Some(e)) }
+                "exit" kw_end() { ast::Statement::Exit(None) }
+                "nextfile" kw_end() { ast::Statement::NextFile }
+                "next" kw_end() { ast::Statement::Next }
+                // delete array[key] / delete array
+                "delete" kw_end() _ l:lvalue() { ast::Statement::Delete(l) }
             }
 
         // 式
         rule expression() -> ast::Expression
+            = e:expr(false) { e }
+
+        // print文の引数では > を比較演算子ではなく出力先の指定として扱う
+        // (print a > "file")
+        rule print_expression() -> ast::Expression
+            = e:expr(true) { e }
+
+        rule expr(in_print: bool) -> ast::Expression
             = precedence! {
                 l:lvalue() _ "=" _ e:@ { ast::Expression::Assign { lval: l, expr: Box::new(e)} }
                 l:lvalue() _ "+=" _ e:@ {
-                    ast::Expression::Assign {
-                        lval: l.clone(), expr: Box::new(ast::Expression::BinaryOp {
-                            op: ast::BOperator::Add,
-                            left: Box::new(ast::Expression::LValue(l)),
-                            right: Box::new(e),
-                        })
-                    }
+                    ast::Expression::OpAssign { op: ast::BOperator::Add, lval: l, expr: Box::new(e) }
                 }
                 l:lvalue() _ "-=" _ e:@ {
-                    ast::Expression::Assign {
-                        lval: l.clone(), expr: Box::new(ast::Expression::BinaryOp {
-                            op: ast::BOperator::Sub,
-                            left: Box::new(ast::Expression::LValue(l)),
-                            right: Box::new(e),
-                        })
-                    }
+                    ast::Expression::OpAssign { op: ast::BOperator::Sub, lval: l, expr: Box::new(e) }
                 }
                 l:lvalue() _ "*=" _ e:@ {
-                    ast::Expression::Assign {
-                        lval: l.clone(), expr: Box::new(ast::Expression::BinaryOp {
-                            op: ast::BOperator::Mul,
-                            left: Box::new(ast::Expression::LValue(l)),
-                            right: Box::new(e),
-                        })
-                    }
+                    ast::Expression::OpAssign { op: ast::BOperator::Mul, lval: l, expr: Box::new(e) }
                 }
                 l:lvalue() _ "/=" _ e:@ {
-                    ast::Expression::Assign {
-                        lval: l.clone(), expr: Box::new(ast::Expression::BinaryOp {
-                            op: ast::BOperator::Div,
-                            left: Box::new(ast::Expression::LValue(l)),
-                            right: Box::new(e),
-                        })
-                    }
+                    ast::Expression::OpAssign { op: ast::BOperator::Div, lval: l, expr: Box::new(e) }
                 }
                 l:lvalue() _ "%=" _ e:@ {
-                    ast::Expression::Assign {
-                        lval: l.clone(), expr: Box::new(ast::Expression::BinaryOp {
-                            op: ast::BOperator::Mod,
-                            left: Box::new(ast::Expression::LValue(l)),
-                            right: Box::new(e),
-                        })
-                    }
+                    ast::Expression::OpAssign { op: ast::BOperator::Mod, lval: l, expr: Box::new(e) }
                 }
                 l:lvalue() _ "^=" _ e:@ {
-                    ast::Expression::Assign {
-                        lval: l.clone(), expr: Box::new(ast::Expression::BinaryOp {
-                            op: ast::BOperator::Pow,
-                            left: Box::new(ast::Expression::LValue(l)),
-                            right: Box::new(e),
-                        })
-                    }
+                    ast::Expression::OpAssign { op: ast::BOperator::Pow, lval: l, expr: Box::new(e) }
+                }
+                --
+                // 三項演算子 (右結合)
+                c:@ _ "?" __ t:expression() __ ":" __ e:(@) {
+                    ast::Expression::Ternary { cond: Box::new(c), then: Box::new(t), els: Box::new(e) }
                 }
                 --
                 l:(@) _ "||" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::Or, left: Box::new(l), right: Box::new(r), } }
                 --
                 l:(@) _ "&&" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::And, left: Box::new(l), right: Box::new(r), } }
                 --
+                // (key) in array
+                l:@ _ "in" kw_end() _ n:name() { ast::Expression::In { expr_list: vec![l], name: n } }
+                --
+                l:(@) _ "~" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::Match, left: Box::new(l), right: Box::new(r), } }
+                l:(@) _ "!~" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::NotMatch, left: Box::new(l), right: Box::new(r), } }
+                --
                 l:(@) _ "<" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::LessThan, left: Box::new(l), right: Box::new(r), } }
                 l:(@) _ "<=" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::LessEqualThan, left: Box::new(l), right: Box::new(r), } }
                 l:(@) _ "!=" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::NotEqual, left: Box::new(l), right: Box::new(r), } }
                 l:(@) _ "==" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::Equal, left: Box::new(l), right: Box::new(r), } }
-                l:(@) _ ">" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::GreaterThan, left: Box::new(l), right: Box::new(r), } }
+                l:(@) _ not_in_print(in_print) ">" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::GreaterThan, left: Box::new(l), right: Box::new(r), } }
                 l:(@) _ ">=" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::GreaterEqualThan, left: Box::new(l), right: Box::new(r), } }
                 --
-                l:(@) _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::Cat, left: Box::new(l), right: Box::new(r), } }
+                // cmd | getline [var]
+                c:@ _ "|" !"|" _ "getline" kw_end() _ l:lvalue()? {
+                    ast::Expression::Getline { kind: ast::GetlineType::Command, src: Some(Box::new(c)), lval: l }
+                }
+                --
+                // 右辺が / + - で始まる場合は割り算，足し算，引き算
+                // (1 -1 は 1 (-1) ではなく 1-1)
+                l:(@) _ !['/' | '+' | '-'] r:@ { ast::Expression::BinaryOp { op: ast::BOperator::Cat, left: Box::new(l), right: Box::new(r), } }
                 --
                 l:(@) _ "+" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::Add, left: Box::new(l), right: Box::new(r), } }
                 l:(@) _ "-" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::Sub, left: Box::new(l), right: Box::new(r), } }
@@ -194,11 +228,22 @@ peg::parser! {
                 l:(@) _ "*" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::Mul, left: Box::new(l), right: Box::new(r), } }
                 l:(@) _ "/" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::Div, left: Box::new(l), right: Box::new(r), } }
                 l:(@) _ "%" _ r:@ { ast::Expression::BinaryOp { op: ast::BOperator::Mod, left: Box::new(l), right: Box::new(r), } }
-                // --
-                // "+" _ e:@ { ast::Expression::BinaryOp { op: ast::BOperator::Mul, left: Box::new(e), right: Box::new(ast::Expression::Value(ast::Value::Num(1.0))), } }
-                // "-" _ e:@ { ast::Expression::BinaryOp { op: ast::BOperator::Mul, left: Box::new(e), right: Box::new(ast::Expression::Value(ast::Value::Num(-1.0))), } }
+                --
+                // 単項演算子は ^ より弱く * より強い (-2^2 == -4)
+                // --x, ++x と区別する
+                "-" !"-" _ e:@ { ast::Expression::Unary { op: ast::UOperator::Minus, expr: Box::new(e) } }
+                "+" !"+" _ e:@ { ast::Expression::Unary { op: ast::UOperator::Plus, expr: Box::new(e) } }
+                "!" _ e:@ { ast::Expression::Unary { op: ast::UOperator::Not, expr: Box::new(e) } }
                 --
                 l:@ _ "^" _ r:(@) { ast::Expression::BinaryOp { op: ast::BOperator::Pow, left: Box::new(l), right: Box::new(r), } }
+                // 2^-1 のように右辺には単項演算子を書ける
+                l:@ _ "^" _ "-" _ r:(@) {
+                    ast::Expression::BinaryOp {
+                        op: ast::BOperator::Pow,
+                        left: Box::new(l),
+                        right: Box::new(ast::Expression::Unary { op: ast::UOperator::Minus, expr: Box::new(r) }),
+                    }
+                }
                 --
                 l:lvalue() _ "++" { ast::Expression::IncDec { op: ast::IncDecType::PostInc, lval: Box::new(l) } }
                 l:lvalue() _ "--" { ast::Expression::IncDec { op: ast::IncDecType::PostDec, lval: Box::new(l) } }
@@ -208,13 +253,34 @@ peg::parser! {
                 --
                 "$" _ e:@ { ast::Expression::GetField(Box::new(e)) }
                 --
+                // (i, j) in array
+                "(" _ e:(expression() **<2,> (_ "," _)) _ ")" _ "in" kw_end() _ n:name() {
+                    ast::Expression::In { expr_list: e, name: n }
+                }
+                // getline [var] < file
+                "getline" kw_end() _ l:lvalue()? _ "<" _ f:getline_src() {
+                    ast::Expression::Getline { kind: ast::GetlineType::File, src: Some(Box::new(f)), lval: l }
+                }
+                // getline [var]
+                "getline" kw_end() _ l:lvalue()? {
+                    ast::Expression::Getline { kind: ast::GetlineType::Main, src: None, lval: l }
+                }
                 n:number() { ast::Expression::Value(ast::Value::Num(n)) }
                 n:string() { ast::Expression::Value(ast::Value::Str(n)) }
+                r:regex() { ast::Expression::Regex(r) }
                 e:func_call() { e }
                 n:lvalue() { ast::Expression::LValue(n) }
                 "(" _ e:expression() _ ")" { e }
             }
 
+        // getline < file のファイル名
+        // getline < "a" "b" は (getline < "a") "b" になる
+        rule getline_src() -> ast::Expression
+            = "(" _ e:expression() _ ")" { e }
+            / "$" _ e:getline_src() { ast::Expression::GetField(Box::new(e)) }
+            / n:string() { ast::Expression::Value(ast::Value::Str(n)) }
+            / l:lvalue() { ast::Expression::LValue(l) }
+
         rule func_call() -> ast::Expression
             = name:name() "(" args:(expression() ** (_ "," _)) ")" {
                 if get_index_from_name(&name).is_some() {
@@ -236,24 +302,74 @@ peg::parser! {
             }
 
         rule lvalue() -> ast::LValue
-            = l:name() "[" e:expression() "]" { ast::LValue::Array { name: l, expr_list: vec![e]} }
+            = l:name() "[" _ e:(expression() ++ (_ "," _)) _ "]" { ast::LValue::Array { name: l, expr_list: e } }
             / l:name() { ast::LValue::Name(l) }
+            / "$" _ e:field_index() { ast::LValue::Field(Box::new(e)) }
+
+        // $の後ろに書けるもの
+        // $i++ は ($i)++，$NF-1 は ($NF)-1
+        rule field_index() -> ast::Expression
+            = "(" _ e:expression() _ ")" { e }
+            / n:number() { ast::Expression::Value(ast::Value::Num(n)) }
+            / "-" _ n:number() { ast::Expression::Value(ast::Value::Num(-n)) }
+            / l:lvalue() { ast::Expression::LValue(l) }
+
+        // 出力先 > file, >> file, | cmd
+        // print > $1 ".txt" のように連結は出力先に含まれる
+        rule redirect() -> ast::Redirect
+            = _ ">>" _ e:print_expression() { ast::Redirect { kind: ast::RedirectType::Append, dest: e } }
+            / _ ">" _ e:print_expression() { ast::Redirect { kind: ast::RedirectType::File, dest: e } }
+            / _ "|" !"|" _ e:print_expression() { ast::Redirect { kind: ast::RedirectType::Pipe, dest: e } }
+
+        // 括弧ありprint文の後に続くもの
+        rule redirect_end() = stmt_end() / _ ['>' | '|']
+
+        // print文の引数の中では失敗する
+        rule not_in_print(in_print: bool)
+            = "" {? if in_print { Err("redirection") } else { Ok(()) } }
 
-        // 数字 (もっと詳しくパースできるように)
+        // 文の終わり
+        rule stmt_end() = _ ([';' | '\n' | '}' | '#'] / ![_])
+
+        // キーワードの直後に名前の文字が続かないことを確認する (index を in と読まない)
+        rule kw_end() = !['a'..='z' | 'A'..='Z' | '_' | '0'..='9']
+
+        // 数字 (1, 1.5, 1., .5, 1e10, 2.5E-3 など)
         pub rule number() -> f64
-            = n:$(['0'..='9']+ ("." ['0'..='9']+)?) {? n.parse::<f64>().or(Err("i64")) }
+            = n:$((['0'..='9']+ ("." ['0'..='9']*)? / "." ['0'..='9']+) (['e' | 'E'] ['+' | '-']? ['0'..='9']+)?)
+              {? n.parse::<f64>().or(Err("f64")) }
 
         // 文字列
         // https://github.com/kevinmehall/rust-peg/issues/314
         rule string() -> String
             = "\"" s:dqc()* "\"" { s.into_iter().collect() }
 
+        // 正規表現 /ere/
+        // エスケープシーケンスの解釈は実行時に行うので，そのままの文字列を返す
+        rule regex() -> String
+            = "/" s:$((regex_bracket() / "\\" [_] / [^ '/' | '\\' | '\n'])*) "/" { s.to_string() }
+
+        // ブラケット式の中では / をエスケープしなくてよい
+        rule regex_bracket()
+            = "[" "^"? "]"? ("[:" ['a'..='z']+ ":]" / "\\" [_] / [^ ']' | '\n'])* "]"
+
+        // 文字列リテラルと同じエスケープシーケンスを解釈する
+        // 解釈できない \c は c にする
+        pub rule escaped() -> String
+            = s:(dqc() / "\"" { '"' } / "\\" c:[_] { c } / "\\" { '\\' })* { s.into_iter().collect() }
+
         rule dqc() -> char
             = [^ '"' | '\\']
             / "\\n" { '\n' }
             / "\\\"" { '"' }
             / "\\\\" { '\\' }
-            / "\\0" { '\0' }
+            / "\\/" { '/' }
+            / "\\a" { '\x07' }
+            / "\\b" { '\x08' }
+            / "\\f" { '\x0c' }
+            / "\\v" { '\x0b' }
+            // 8進数 \034 など
+            / "\\" o:$(['0'..='7']*<1,3>) {? u32::from_str_radix(o, 8).ok().and_then(char::from_u32).ok_or("octal escape") }
             / "\\r" { '\r' }
             / "\\t" { '\t' }
             / "\\e" { '\x1b' }
@@ -270,7 +386,7 @@ peg::parser! {
 pub fn is_awk_reserved_name(name: &str) -> bool {
     let list = [
         "BEGIN", "delete", "END", "function", "in", "printf", "break", "do", "exit", "getline",
-        "next", "return", "continue", "else", "for", "if", "print", "while",
+        "next", "nextfile", "return", "continue", "else", "for", "if", "print", "while",
     ];
     list.iter().any(|n| n == &name)
 }
@@ -280,14 +396,17 @@ fn test_parser() {
     let prg = " BEGIN { print( 123 + 333 , 456 ) } ";
     let expect = vec![ast::Item::PatternAction(ast::PatternAction {
         pattern: ast::Pattern::Begin,
-        action: ast::Statement::Action(vec![ast::Statement::Print(vec![
-            ast::Expression::BinaryOp {
-                op: ast::BOperator::Add,
-                left: Box::new(ast::Expression::Value(ast::Value::Num(123.0))),
-                right: Box::new(ast::Expression::Value(ast::Value::Num(333.0))),
-            },
-            ast::Expression::Value(ast::Value::Num(456.0)),
-        ])]),
+        action: ast::Statement::Action(vec![ast::Statement::Print {
+            args: vec![
+                ast::Expression::BinaryOp {
+                    op: ast::BOperator::Add,
+                    left: Box::new(ast::Expression::Value(ast::Value::Num(123.0))),
+                    right: Box::new(ast::Expression::Value(ast::Value::Num(333.0))),
+                },
+                ast::Expression::Value(ast::Value::Num(456.0)),
+            ],
+            redirect: None,
+        }]),
     })];
     let actual = awk::prog(prg).unwrap();
 
@@ -0,0 +1,354 @@
+use regex::RegexBuilder;
+use regex_automata::{meta, util::syntax, Anchored, Input, MatchKind};
+
+// ERE
+// POSIXの拡張正規表現(ERE)をregexクレートの構文に変換してコンパイルする
+//
+// regexクレートとの違い
+//   ・ブラケット式の中の [ & ~ - はregexクレートでは集合演算に使われるのでエスケープする
+//   ・ [.c.] [=c=] は一文字として扱う
+//   ・区間表現として正しくない { } はリテラルとして扱う
+//   ・AWKのエスケープシーケンス(\n \t \/ \ddd など)を解釈する
+//   ・ . は改行にもマッチする
+//   ・マッチはPOSIXと同じ最左最長で行う
+//     (regexクレートで最も左の開始位置を探し，そこから最長のマッチをregex-automataで探す)
+
+pub fn compile(ere: &str) -> Result<Regex, String> {
+    let pattern = translate(ere);
+    let first = RegexBuilder::new(&pattern)
+        .dot_matches_new_line(true)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let longest = meta::Regex::builder()
+        .configure(meta::Config::new().match_kind(MatchKind::All))
+        .syntax(syntax::Config::new().dot_matches_new_line(true))
+        .build(&pattern)
+        .map_err(|e| e.to_string())?;
+    Ok(Regex { first, longest })
+}
+
+// コンパイルしたERE
+#[derive(Clone)]
+pub struct Regex {
+    // 開始位置を探す (最左優先)
+    first: regex::Regex,
+    // 開始位置を固定して最長のマッチを探す
+    longest: meta::Regex,
+}
+
+// マッチした範囲
+pub struct Match<'h> {
+    haystack: &'h str,
+    start: usize,
+    end: usize,
+}
+
+impl<'h> Match<'h> {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+    pub fn end(&self) -> usize {
+        self.end
+    }
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+    pub fn as_str(&self) -> &'h str {
+        &self.haystack[self.start..self.end]
+    }
+}
+
+impl Regex {
+    pub fn is_match(&self, haystack: &str) -> bool {
+        self.first.is_match(haystack)
+    }
+
+    // 最左最長のマッチを探す
+    pub fn find<'h>(&self, haystack: &'h str) -> Option<Match<'h>> {
+        self.find_at(haystack, 0)
+    }
+
+    // start以降で最左最長のマッチを探す (^ などはhaystackの先頭を基準にする)
+    pub fn find_at<'h>(&self, haystack: &'h str, start: usize) -> Option<Match<'h>> {
+        let start = self.first.find_at(haystack, start)?.start();
+        let input = Input::new(haystack).range(start..).anchored(Anchored::Yes);
+        let end = self.longest.search(&input)?.end();
+        Some(Match {
+            haystack,
+            start,
+            end,
+        })
+    }
+
+    // 重ならないマッチを順に返す
+    // 直前のマッチの終わりにある空のマッチは数えない
+    pub fn find_iter<'r, 'h>(&'r self, haystack: &'h str) -> Matches<'r, 'h> {
+        Matches {
+            re: self,
+            haystack,
+            pos: 0,
+            last_end: None,
+        }
+    }
+
+    // マッチした部分で区切る
+    // 空のマッチは区切りにしない (FS = "a*" で "xaay" は "x" と "y")
+    pub fn split<'h>(&self, haystack: &'h str) -> Vec<&'h str> {
+        let mut fields = vec![];
+        let mut last = 0;
+        for m in self.find_iter(haystack).filter(|m| !m.is_empty()) {
+            fields.push(&haystack[last..m.start()]);
+            last = m.end();
+        }
+        fields.push(&haystack[last..]);
+        fields
+    }
+}
+
+pub struct Matches<'r, 'h> {
+    re: &'r Regex,
+    haystack: &'h str,
+    pos: usize,
+    last_end: Option<usize>,
+}
+
+impl<'h> Iterator for Matches<'_, 'h> {
+    type Item = Match<'h>;
+
+    fn next(&mut self) -> Option<Match<'h>> {
+        loop {
+            if self.pos > self.haystack.len() {
+                return None;
+            }
+            let m = self.re.find_at(self.haystack, self.pos)?;
+            // 空のマッチの後は一文字進める
+            self.pos = if m.is_empty() {
+                m.end()
+                    + self.haystack[m.end()..]
+                        .chars()
+                        .next()
+                        .map_or(1, |c| c.len_utf8())
+            } else {
+                m.end()
+            };
+            if m.is_empty() && self.last_end == Some(m.end()) {
+                continue;
+            }
+            self.last_end = Some(m.end());
+            return Some(m);
+        }
+    }
+}
+
+fn translate(ere: &str) -> String {
+    let chars: Vec<char> = ere.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                let (c, len) = escape(&chars[i + 1..]);
+                push_literal(&mut out, c);
+                i += 1 + len;
+            }
+            '[' => {
+                i = bracket(&chars, i, &mut out);
+            }
+            '{' => {
+                // 区間表現 {n} {n,} {n,m} でなければリテラル
+                match interval(&chars[i..]) {
+                    Some(len) if !out.is_empty() => {
+                        out.extend(&chars[i..i + len]);
+                        i += len;
+                    }
+                    _ => {
+                        out.push_str("\\{");
+                        i += 1;
+                    }
+                }
+            }
+            '}' => {
+                out.push_str("\\}");
+                i += 1;
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+// \の後ろの文字列を解釈し，(文字, 消費した文字数)を返す
+fn escape(rest: &[char]) -> (char, usize) {
+    match rest.first() {
+        None => ('\\', 0),
+        Some('n') => ('\n', 1),
+        Some('t') => ('\t', 1),
+        Some('r') => ('\r', 1),
+        Some('f') => ('\x0c', 1),
+        Some('v') => ('\x0b', 1),
+        Some('b') => ('\x08', 1),
+        Some('a') => ('\x07', 1),
+        Some('0'..='7') => {
+            // 8進数で3桁まで
+            let len = rest
+                .iter()
+                .take(3)
+                .take_while(|c| ('0'..='7').contains(c))
+                .count();
+            let code = rest[..len]
+                .iter()
+                .fold(0, |acc, c| acc * 8 + c.to_digit(8).unwrap());
+            (char::from_u32(code).unwrap_or('\0'), len)
+        }
+        Some(c) => (*c, 1),
+    }
+}
+
+// 一文字をregexクレートのリテラルとして追加する
+fn push_literal(out: &mut String, c: char) {
+    if c.is_ascii_punctuation() {
+        out.push('\\');
+        out.push(c);
+    } else if c.is_control() {
+        out.push_str(&format!("\\x{{{:x}}}", c as u32));
+    } else {
+        out.push(c);
+    }
+}
+
+// {n} {n,} {n,m} の長さを返す
+fn interval(rest: &[char]) -> Option<usize> {
+    let close = rest.iter().position(|c| *c == '}')?;
+    let body: String = rest[1..close].iter().collect();
+    let mut parts = body.splitn(2, ',');
+    let min = parts.next()?;
+    if min.is_empty() || !min.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    if let Some(max) = parts.next() {
+        if !max.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+    }
+    Some(close + 1)
+}
+
+// ブラケット式 [...] を変換し，次の位置を返す
+// 閉じ括弧がない場合は [ をリテラルとして扱う
+fn bracket(chars: &[char], start: usize, out: &mut String) -> usize {
+    let mut i = start + 1;
+    let mut class = String::from("[");
+    if chars.get(i) == Some(&'^') {
+        class.push('^');
+        i += 1;
+    }
+    // 先頭の ] はリテラル
+    // 範囲 a-z の - はNoneとして覚えておく
+    let mut items: Vec<Option<char>> = vec![];
+    let mut first = true;
+    loop {
+        let c = match chars.get(i) {
+            Some(c) => *c,
+            None => {
+                out.push_str("\\[");
+                return start + 1;
+            }
+        };
+        if c == ']' && !first {
+            break;
+        }
+        first = false;
+        if c == '[' && matches!(chars.get(i + 1), Some(':' | '.' | '=')) {
+            let delim = chars[i + 1];
+            let end = (i + 2..chars.len().saturating_sub(1))
+                .find(|j| chars[*j] == delim && chars[j + 1] == ']');
+            if let Some(end) = end {
+                let body: String = chars[i + 2..end].iter().collect();
+                if delim == ':' {
+                    flush_items(&mut class, &mut items);
+                    class.push_str(&format!("[:{}:]", body));
+                } else {
+                    items.extend(body.chars().map(Some));
+                }
+                i = end + 2;
+                continue;
+            }
+        }
+        if c == '\\' {
+            let (e, len) = escape(&chars[i + 1..]);
+            items.push(Some(e));
+            i += 1 + len;
+            continue;
+        }
+        if c == '-' && !items.is_empty() && chars.get(i + 1).is_some_and(|n| *n != ']') {
+            items.push(None);
+            i += 1;
+            continue;
+        }
+        items.push(Some(c));
+        i += 1;
+    }
+    flush_items(&mut class, &mut items);
+    class.push(']');
+    out.push_str(&class);
+    i + 1
+}
+
+fn flush_items(class: &mut String, items: &mut Vec<Option<char>>) {
+    for item in items.iter() {
+        match item {
+            Some(c) => push_literal(class, *c),
+            None => class.push('-'),
+        }
+    }
+    items.clear();
+}
+
+#[test]
+fn test_translate() {
+    let set = [
+        ("^a+b?$", "^a+b?$"),
+        ("a{2,3}", "a{2,3}"),
+        ("{a", "\\{a"),
+        ("a\\/b", "a\\/b"),
+        ("[]a]", "[\\]a]"),
+        ("[^a-z&]", "[^a-z\\&]"),
+        ("[[:alpha:]_]", "[[:alpha:]\\_]"),
+        ("[a-]", "[a\\-]"),
+        ("a|b", "a|b"),
+    ];
+    for (ere, expect) in set {
+        assert_eq!(expect, translate(ere));
+    }
+}
+
+#[test]
+fn test_compile() {
+    assert!(compile("^[[:digit:]]{3}-[0-9]+$")
+        .unwrap()
+        .is_match("123-45"));
+    assert!(compile("x[[]y").unwrap().is_match("x[y"));
+    assert!(!compile("^(ab|cd)$").unwrap().is_match("abcd"));
+}
+
+#[test]
+fn test_leftmost_longest() {
+    let set = [
+        ("ab|abcd", "xabcd", Some("abcd")),
+        ("x|xyz", "xyz", Some("xyz")),
+        ("(a|ab)(c|bcd)", "abcd", Some("abcd")),
+        ("b*", "abbb", Some("")),
+        ("b+|bbb", "abbbb", Some("bbbb")),
+        ("z", "abc", None),
+    ];
+    for (ere, s, expect) in set {
+        let re = compile(ere).unwrap();
+        assert_eq!(expect, re.find(s).map(|m| m.as_str()), "{} {}", ere, s);
+    }
+    let re = compile("x*").unwrap();
+    let found: Vec<_> = re.find_iter("axxb").map(|m| (m.start(), m.end())).collect();
+    assert_eq!(vec![(0, 0), (1, 3), (4, 4)], found);
+}
@@ -0,0 +1,378 @@
+use crate::ast::Value;
+
+// printf / sprintf の書式化
+//
+// 変換指定 %[フラグ][幅][.精度]変換
+//   フラグ: - + 空白 # 0
+//   幅，精度: 数字または * (引数から取る)
+//   変換: d i o x X u c s e E f F g G %
+//
+// 引数が足りない場合は未初期化の値として扱う
+// 解釈できない変換指定はそのまま出力する
+
+#[derive(Default)]
+struct Spec {
+    left: bool,  // -
+    plus: bool,  // +
+    space: bool, // 空白
+    alt: bool,   // #
+    zero: bool,  // 0
+    width: usize,
+    prec: Option<usize>,
+}
+
+pub fn format(fmt: &str, args: &[Value], convfmt: &str) -> String {
+    let chars: Vec<char> = fmt.chars().collect();
+    let mut args = args.iter();
+    let mut next_arg = || args.next().cloned().unwrap_or(Value::None);
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '%' {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        let mut spec = Spec::default();
+        // フラグ
+        while let Some(c) = chars.get(i) {
+            match c {
+                '-' => spec.left = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                '#' => spec.alt = true,
+                '0' => spec.zero = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        // 幅 (負の値は左寄せ)
+        if chars.get(i) == Some(&'*') {
+            let w = next_arg().to_float() as i64;
+            if w < 0 {
+                spec.left = true;
+            }
+            spec.width = w.unsigned_abs() as usize;
+            i += 1;
+        } else {
+            spec.width = digits(&chars, &mut i);
+        }
+        // 精度 (負の値は指定なし)
+        if chars.get(i) == Some(&'.') {
+            i += 1;
+            if chars.get(i) == Some(&'*') {
+                let p = next_arg().to_float() as i64;
+                spec.prec = if p < 0 { None } else { Some(p as usize) };
+                i += 1;
+            } else {
+                spec.prec = Some(digits(&chars, &mut i));
+            }
+        }
+        let conv = match chars.get(i) {
+            Some(c) => *c,
+            None => {
+                out.extend(&chars[start..]);
+                break;
+            }
+        };
+        i += 1;
+        match conv {
+            '%' => out.push('%'),
+            'd' | 'i' => {
+                let n = next_arg().to_float();
+                out.push_str(&format_signed(n, &spec));
+            }
+            'o' | 'x' | 'X' | 'u' => {
+                let n = next_arg().to_float();
+                out.push_str(&format_unsigned(n, conv, &spec));
+            }
+            'c' => {
+                let arg = next_arg();
+                let c = match arg.as_number() {
+                    Some(n) => char::from_u32(n as u32).unwrap_or('\0').to_string(),
+                    None => arg.to_str_fmt(convfmt).chars().take(1).collect(),
+                };
+                out.push_str(&pad(&c, &spec, false));
+            }
+            's' => {
+                let s = next_arg().to_str_fmt(convfmt);
+                let s: String = match spec.prec {
+                    Some(p) => s.chars().take(p).collect(),
+                    None => s,
+                };
+                out.push_str(&pad(&s, &spec, false));
+            }
+            'e' | 'E' | 'f' | 'F' | 'g' | 'G' => {
+                let n = next_arg().to_float();
+                out.push_str(&format_float(n, conv, &spec));
+            }
+            _ => out.extend(&chars[start..i]),
+        }
+    }
+    out
+}
+
+fn digits(chars: &[char], i: &mut usize) -> usize {
+    let mut n = 0;
+    while let Some(d) = chars.get(*i).and_then(|c| c.to_digit(10)) {
+        n = n * 10 + d as usize;
+        *i += 1;
+    }
+    n
+}
+
+// 符号を付ける
+fn sign(negative: bool, spec: &Spec) -> &'static str {
+    if negative {
+        "-"
+    } else if spec.plus {
+        "+"
+    } else if spec.space {
+        " "
+    } else {
+        ""
+    }
+}
+
+// 幅に合わせて埋める
+// zeroがtrueの場合は符号と接頭辞(0x)の後ろを0で埋める
+fn pad(s: &str, spec: &Spec, zero: bool) -> String {
+    let len = s.chars().count();
+    if len >= spec.width {
+        return s.to_string();
+    }
+    let fill = spec.width - len;
+    if spec.left {
+        format!("{}{}", s, " ".repeat(fill))
+    } else if zero && spec.zero {
+        let prefix_len = if s.starts_with("0x") || s.starts_with("0X") {
+            2
+        } else if s.starts_with(['-', '+', ' ']) {
+            1
+        } else {
+            0
+        };
+        format!(
+            "{}{}{}",
+            &s[..prefix_len],
+            "0".repeat(fill),
+            &s[prefix_len..]
+        )
+    } else {
+        format!("{}{}", " ".repeat(fill), s)
+    }
+}
+
+// 整数の桁数を精度に合わせる (精度0で値が0の場合は空)
+fn int_digits(digits: String, spec: &Spec) -> String {
+    match spec.prec {
+        Some(0) if digits == "0" => String::new(),
+        Some(p) if digits.len() < p => format!("{}{}", "0".repeat(p - digits.len()), digits),
+        _ => digits,
+    }
+}
+
+// %d %i
+fn format_signed(n: f64, spec: &Spec) -> String {
+    if !n.is_finite() {
+        return format_float(n, 'f', spec);
+    }
+    let n = n.trunc();
+    let digits = int_digits(format!("{:.0}", n.abs()), spec);
+    let s = format!("{}{}", sign(n < 0.0, spec), digits);
+    pad(&s, spec, spec.prec.is_none())
+}
+
+// %o %x %X %u
+// 負の値は2の補数として扱う
+fn format_unsigned(n: f64, conv: char, spec: &Spec) -> String {
+    if !n.is_finite() {
+        return format_float(n, 'f', spec);
+    }
+    let u = if n < 0.0 { n as i64 as u64 } else { n as u64 };
+    let digits = match conv {
+        'o' => format!("{:o}", u),
+        'x' => format!("{:x}", u),
+        'X' => format!("{:X}", u),
+        _ => u.to_string(),
+    };
+    let mut digits = int_digits(digits, spec);
+    if spec.alt {
+        match conv {
+            'o' if !digits.starts_with('0') => digits.insert(0, '0'),
+            'x' if u != 0 => digits.insert_str(0, "0x"),
+            'X' if u != 0 => digits.insert_str(0, "0X"),
+            _ => {}
+        }
+    }
+    pad(&digits, spec, spec.prec.is_none())
+}
+
+// %e %E %f %F %g %G
+fn format_float(n: f64, conv: char, spec: &Spec) -> String {
+    let upper = conv.is_ascii_uppercase();
+    if !n.is_finite() {
+        let s = if n.is_nan() { "nan" } else { "inf" };
+        let s = if upper {
+            s.to_uppercase()
+        } else {
+            s.to_string()
+        };
+        let negative = n.is_sign_negative() && !n.is_nan();
+        return pad(&format!("{}{}", sign(negative, spec), s), spec, false);
+    }
+    let prec = spec.prec.unwrap_or(6);
+    let body = match conv.to_ascii_lowercase() {
+        'f' => format!("{:.*}", prec, n.abs()),
+        'e' => exponential(n.abs(), prec),
+        _ => {
+            // %g は指数が -4 以上精度未満なら %f，それ以外は %e
+            let p = if prec == 0 { 1 } else { prec };
+            let e = exponential(n.abs(), p - 1);
+            let exp: i32 = e[e.find('e').unwrap() + 1..].parse().unwrap();
+            let s = if exp < -4 || exp >= p as i32 {
+                e
+            } else {
+                format!("{:.*}", (p as i32 - 1 - exp) as usize, n.abs())
+            };
+            if spec.alt {
+                s
+            } else {
+                strip_zeros(&s)
+            }
+        }
+    };
+    let mut body = if upper { body.to_uppercase() } else { body };
+    if spec.alt && !body.contains('.') {
+        // # は小数点を必ず付ける
+        match body.find(['e', 'E']) {
+            Some(pos) => body.insert(pos, '.'),
+            None => body.push('.'),
+        }
+    }
+    let s = format!("{}{}", sign(n.is_sign_negative() && n != 0.0, spec), body);
+    pad(&s, spec, true)
+}
+
+// Cと同じ 1.5e+01 の形式
+fn exponential(n: f64, prec: usize) -> String {
+    let s = format!("{:.*e}", prec, n);
+    let (mantissa, exp) = s.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    format!(
+        "{}e{}{:02}",
+        mantissa,
+        if exp < 0 { '-' } else { '+' },
+        exp.abs()
+    )
+}
+
+// 小数部の末尾の0を取り除く
+fn strip_zeros(s: &str) -> String {
+    let (mantissa, exp) = match s.find('e') {
+        Some(pos) => (&s[..pos], &s[pos..]),
+        None => (s, ""),
+    };
+    let mantissa = if mantissa.contains('.') {
+        mantissa.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        mantissa
+    };
+    format!("{}{}", mantissa, exp)
+}
+
+#[test]
+fn test_format() {
+    let num = |n: f64| Value::Num(n);
+    let str = |s: &str| Value::Str(s.to_string());
+    let set = [
+        (
+            "%d|%i|%5d|%-5d|%05d",
+            vec![num(42.9), num(-3.0), num(7.0), num(7.0), num(-7.0)],
+            "42|-3|    7|7    |-0007",
+        ),
+        (
+            "%+d|% d|%.3d|%.0d|%5.3d",
+            vec![num(5.0), num(5.0), num(5.0), num(0.0), num(-5.0)],
+            "+5| 5|005|| -005",
+        ),
+        (
+            "%o|%#o|%x|%#X|%u|%x",
+            vec![
+                num(8.0),
+                num(8.0),
+                num(255.0),
+                num(255.0),
+                num(3.0),
+                num(-1.0),
+            ],
+            "10|010|ff|0XFF|3|ffffffffffffffff",
+        ),
+        (
+            "%c%c|%3c",
+            vec![num(65.0), str("hello"), str("z")],
+            "Ah|  z",
+        ),
+        (
+            "%s|%5s|%-5s|%.2s|%*s|%-*s|",
+            vec![
+                str("ab"),
+                str("ab"),
+                str("ab"),
+                str("abc"),
+                num(3.0),
+                str("x"),
+                num(3.0),
+                str("y"),
+            ],
+            "ab|   ab|ab   |ab|  x|y  |",
+        ),
+        (
+            "%f|%.2f|%8.3f|%-8.1f|%08.2f|%+.1f",
+            vec![
+                num(1.23456),
+                num(2.005),
+                num(-1.5),
+                num(1.25),
+                num(-3.5),
+                num(2.0),
+            ],
+            "1.234560|2.00|  -1.500|1.2     |-0003.50|+2.0",
+        ),
+        (
+            "%e|%.2E|%.0e|%#.0e",
+            vec![num(12345.678), num(0.000123), num(5.0), num(5.0)],
+            "1.234568e+04|1.23E-04|5e+00|5.e+00",
+        ),
+        (
+            "%g|%g|%g|%G|%.3g|%#g|%g",
+            vec![
+                num(100000.0),
+                num(1000000.0),
+                num(0.0001),
+                num(0.00001),
+                num(1.23456),
+                num(1.5),
+                num(0.0),
+            ],
+            "100000|1e+06|0.0001|1E-05|1.23|1.50000|0",
+        ),
+        ("%d%%|%z|%", vec![num(50.0)], "50%|%z|%"),
+        ("%s-%d-%s", vec![str("a")], "a-0-"),
+        (
+            "%.*f|%*d",
+            vec![num(1.0), num(2.25), num(-4.0), num(1.0)],
+            "2.2|1   ",
+        ),
+        (
+            "%f|%d|%E",
+            vec![num(f64::INFINITY), num(f64::NEG_INFINITY), num(f64::NAN)],
+            "inf|-inf|NAN",
+        ),
+    ];
+    for (fmt, args, expect) in set {
+        assert_eq!(expect, format(fmt, &args, "%.6g"), "{}", fmt);
+    }
+}
@@ -1,8 +1,9 @@
 use crate::ast::Value;
+use crate::vm::format;
 use crate::vm::VM;
 use rand::prelude::*;
-use std::process::Command;
 use std::io::{stdout, Write};
+use std::process::Command;
 
 pub fn ifunc_sin(vm: &mut VM) {
     let arg = vm.stack.pop().unwrap();
@@ -24,13 +25,13 @@ pub fn ifunc_exp(vm: &mut VM) {
 
 pub fn ifunc_tolower(vm: &mut VM) {
     let arg = vm.stack.pop().unwrap();
-    let ret = Value::Str(arg.to_str().to_lowercase());
+    let ret = Value::Str(arg.to_str_fmt(&vm.convfmt).to_lowercase());
     vm.stack.push(ret);
 }
 
 pub fn ifunc_toupper(vm: &mut VM) {
     let arg = vm.stack.pop().unwrap();
-    let ret = Value::Str(arg.to_str().to_uppercase());
+    let ret = Value::Str(arg.to_str_fmt(&vm.convfmt).to_uppercase());
     vm.stack.push(ret);
 }
 
@@ -69,14 +70,14 @@ pub fn ifunc_atan2(vm: &mut VM) {
 }
 
 pub fn ifunc_length(vm: &mut VM) {
-    let arg = vm.stack.pop().unwrap().to_str();
+    let arg = vm.pop_str();
     let ret = arg.chars().count();
     vm.stack.push(Value::Num(ret as f64));
 }
 
 pub fn ifunc_index(vm: &mut VM) {
-    let s = vm.stack.pop().unwrap().to_str();
-    let t = vm.stack.pop().unwrap().to_str();
+    let s = vm.pop_str();
+    let t = vm.pop_str();
     let ret = if let Some(idx) = s.find(&t) {
         s[..idx].chars().count() + 1
     } else {
@@ -87,7 +88,7 @@ pub fn ifunc_index(vm: &mut VM) {
 
 pub fn ifunc_system(vm: &mut VM) {
     // 現状stdin/outは書き換えられない
-    let arg = vm.stack.pop().unwrap().to_str();
+    let arg = vm.pop_str();
     let mut cmd = Command::new("sh")
         .arg("-c")
         .arg(arg)
@@ -96,6 +97,134 @@ pub fn ifunc_system(vm: &mut VM) {
     let _ = cmd.wait();
 }
 
+pub fn ifunc_substr(vm: &mut VM) {
+    let argc = vm.stack.pop().unwrap().to_float() as usize;
+    let s: Vec<char> = vm.pop_str().chars().collect();
+    // 位置は1から数え，小数は丸める
+    let start = vm.stack.pop().unwrap().to_float().round();
+    let end = if argc == 3 {
+        start + vm.stack.pop().unwrap().to_float().round()
+    } else {
+        f64::INFINITY
+    };
+    let start = start.max(1.0);
+    let end = end.min(s.len() as f64 + 1.0);
+    // NaNの場合もここで空になる
+    let ret = if start < end {
+        s[start as usize - 1..end as usize - 1].iter().collect()
+    } else {
+        String::new()
+    };
+    vm.stack.push(Value::Str(ret));
+}
+
+// 分割した要素と個数を積む．配列への代入はFillArrayで行う
+// 引数の個数の上に，第3引数が正規表現リテラルかどうかが積まれている
+pub fn ifunc_split(vm: &mut VM) {
+    let is_regex = vm.stack.pop().unwrap().is_true();
+    let argc = vm.stack.pop().unwrap().to_float() as usize;
+    let s = vm.pop_str();
+    let fields = if argc == 3 {
+        let fs = vm.pop_str();
+        if is_regex {
+            vm.split_regex(&s, &fs)
+        } else {
+            vm.split(&s, &fs)
+        }
+    } else {
+        let fs = vm.fs.clone();
+        vm.split(&s, &fs)
+    };
+    let len = fields.len();
+    for f in fields {
+        vm.stack.push(Value::StrNum(f));
+    }
+    vm.stack.push(Value::Num(len as f64));
+}
+
+pub fn ifunc_sub(vm: &mut VM) {
+    substitute(vm, false);
+}
+
+pub fn ifunc_gsub(vm: &mut VM) {
+    substitute(vm, true);
+}
+
+// 置き換えた後の文字列と置き換えた個数を積む
+// (代入は続くSetIfで置き換えがあった場合だけ行う)
+fn substitute(vm: &mut VM, global: bool) {
+    let ere = vm.pop_str();
+    let repl: Vec<char> = vm.pop_str().chars().collect();
+    // 対象を省略した場合も$0がコンパイル時に積まれている
+    let target = vm.stack.pop().unwrap();
+    let s = target.to_str_fmt(&vm.convfmt);
+    let re = vm.regex(&ere);
+    let mut ret = String::new();
+    let mut count = 0;
+    let mut last = 0;
+    for m in re.find_iter(&s) {
+        ret.push_str(&s[last..m.start()]);
+        // & はマッチした文字列，\& は & そのもの
+        let mut i = 0;
+        while i < repl.len() {
+            match (repl[i], repl.get(i + 1)) {
+                ('\\', Some('&')) | ('\\', Some('\\')) => {
+                    ret.push(repl[i + 1]);
+                    i += 2;
+                    continue;
+                }
+                ('&', _) => ret.push_str(m.as_str()),
+                (c, _) => ret.push(c),
+            }
+            i += 1;
+        }
+        last = m.end();
+        count += 1;
+        if !global {
+            break;
+        }
+    }
+    if count == 0 {
+        vm.stack.push(target);
+        vm.stack.push(Value::Num(0.0));
+        return;
+    }
+    ret.push_str(&s[last..]);
+    vm.stack.push(Value::Str(ret));
+    vm.stack.push(Value::Num(count as f64));
+}
+
+// 戻り値(RSTART)，RLENGTH，RSTARTの順に積む
+pub fn ifunc_match(vm: &mut VM) {
+    let s = vm.pop_str();
+    let ere = vm.pop_str();
+    let re = vm.regex(&ere);
+    let (start, length) = match re.find(&s) {
+        Some(m) => (
+            s[..m.start()].chars().count() as f64 + 1.0,
+            m.as_str().chars().count() as f64,
+        ),
+        None => (0.0, -1.0),
+    };
+    vm.stack.push(Value::Num(start));
+    vm.stack.push(Value::Num(length));
+    vm.stack.push(Value::Num(start));
+}
+
+pub fn ifunc_sprintf(vm: &mut VM) {
+    let argc = vm.stack.pop().unwrap().to_float() as usize;
+    let fmt = vm.pop_str();
+    let args: Vec<Value> = (1..argc).map(|_| vm.stack.pop().unwrap()).collect();
+    vm.stack
+        .push(Value::Str(format::format(&fmt, &args, &vm.convfmt)));
+}
+
+pub fn ifunc_close(vm: &mut VM) {
+    let name = vm.pop_str();
+    let ret = vm.close(&name);
+    vm.stack.push(Value::Num(ret as f64));
+}
+
 pub fn ifunc_flush(_vm: &mut VM) {
     stdout().flush().unwrap();
 }
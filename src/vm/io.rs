@@ -0,0 +1,244 @@
+use super::ere::Regex;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::{Child, Command, Stdio};
+
+// 入出力
+// getline < file, cmd | getline, print > file, print | cmd で開かれたファイルやコマンドを管理する
+// 開いたものはVMが名前をキーにして持ち，close()されるまで使い回す
+
+pub struct Input {
+    reader: Box<dyn BufRead>,
+    buf: RecordBuf,
+    // cmd | getline の場合はコマンドのプロセス
+    child: Option<Child>,
+}
+
+impl Input {
+    // getline < file
+    pub fn open_file(name: &str) -> std::io::Result<Input> {
+        let reader: Box<dyn BufRead> = if name == "-" || name == "/dev/stdin" {
+            Box::new(BufReader::new(std::io::stdin()))
+        } else {
+            Box::new(BufReader::new(File::open(name)?))
+        };
+        Ok(Input {
+            reader,
+            buf: RecordBuf::default(),
+            child: None,
+        })
+    }
+
+    // cmd | getline
+    pub fn open_command(cmd: &str) -> std::io::Result<Input> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(Input {
+            reader: Box::new(BufReader::new(stdout)),
+            buf: RecordBuf::default(),
+            child: Some(child),
+        })
+    }
+
+    pub fn read_record(&mut self, sep: &RecordSep) -> std::io::Result<Option<(String, String)>> {
+        self.buf.read_record(&mut self.reader, sep)
+    }
+
+    // 閉じて終了ステータスを返す
+    pub fn close(self) -> i32 {
+        // 読み残しがあってもコマンドが終了できるように先に閉じる
+        drop(self.reader);
+        match self.child {
+            Some(mut child) => match child.wait() {
+                Ok(status) => status.code().unwrap_or(-1),
+                Err(_) => -1,
+            },
+            None => 0,
+        }
+    }
+}
+
+pub struct Output {
+    writer: Box<dyn Write>,
+    // print | cmd の場合はコマンドのプロセス
+    child: Option<Child>,
+}
+
+impl Output {
+    // print > file, print >> file
+    pub fn open_file(name: &str, append: bool) -> std::io::Result<Output> {
+        let writer: Box<dyn Write> = if name == "/dev/stderr" {
+            Box::new(std::io::stderr())
+        } else {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .truncate(!append)
+                .open(name)?;
+            Box::new(BufWriter::new(file))
+        };
+        Ok(Output {
+            writer,
+            child: None,
+        })
+    }
+
+    // print | cmd
+    pub fn open_command(cmd: &str) -> std::io::Result<Output> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        Ok(Output {
+            writer: Box::new(BufWriter::new(stdin)),
+            child: Some(child),
+        })
+    }
+
+    // 閉じて終了ステータスを返す
+    pub fn close(mut self) -> i32 {
+        let flushed = self.writer.flush().is_ok();
+        // コマンドに入力の終わりを伝える
+        drop(self.writer);
+        match self.child {
+            Some(mut child) => match child.wait() {
+                Ok(status) => status.code().unwrap_or(-1),
+                Err(_) => -1,
+            },
+            None if flushed => 0,
+            None => -1,
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// レコードセパレータ (RS)
+pub enum RecordSep {
+    // 一文字 (RS = "\n" など)
+    Char(char),
+    // 空行で区切る (RS = "")
+    Paragraph,
+    // 二文字以上はERE
+    Regex(Regex),
+}
+
+// セパレータを探すために読み込んだが，まだレコードとして返していない文字列を持つ
+// pendingのstartより前は返し終わった部分で，読み足すときにまとめて取り除く
+#[derive(Default)]
+pub struct RecordBuf {
+    pending: String,
+    start: usize,
+    eof: bool,
+}
+
+impl RecordBuf {
+    // レコードを一つ読み込み，(レコード, 実際のセパレータ(RT))を返す
+    // 読むものがなければNoneを返す
+    pub fn read_record(
+        &mut self,
+        reader: &mut dyn BufRead,
+        sep: &RecordSep,
+    ) -> std::io::Result<Option<(String, String)>> {
+        // ここより前にはセパレータがないことが分かっている位置
+        let mut scanned = self.start;
+        loop {
+            if let RecordSep::Paragraph = sep {
+                // 先頭の空行は読み飛ばす
+                let rest = &self.pending[self.start..];
+                self.start += rest.len() - rest.trim_start_matches('\n').len();
+                scanned = scanned.max(self.start);
+            }
+            match self.find_separator(sep, scanned) {
+                Ok((start, end)) => {
+                    let rt = self.pending[start..end].to_string();
+                    let record = self.pending[self.start..start].to_string();
+                    self.start = end;
+                    return Ok(Some((record, rt)));
+                }
+                Err(next) => scanned = next,
+            }
+            if self.eof {
+                if self.start == self.pending.len() {
+                    return Ok(None);
+                }
+                let mut record = self.pending[self.start..].to_string();
+                self.start = self.pending.len();
+                let mut rt = String::new();
+                if let RecordSep::Paragraph = sep {
+                    // 最後の改行はレコードに含めない
+                    let len = record.trim_end_matches('\n').len();
+                    rt = record.split_off(len);
+                }
+                return Ok(Some((record, rt)));
+            }
+            // 返し終わった部分を取り除いてから一行ずつ読み足す
+            self.pending.drain(..self.start);
+            scanned -= self.start;
+            self.start = 0;
+            if reader.read_line(&mut self.pending)? == 0 {
+                self.eof = true;
+            }
+        }
+    }
+
+    // scanned以降からセパレータの位置を探す
+    // 見つからなければ，次に探し始める位置をErrで返す
+    // 読み足すと長くなるかもしれないセパレータ(末尾の \n\n など)はEOFまで確定しない
+    fn find_separator(&self, sep: &RecordSep, scanned: usize) -> Result<(usize, usize), usize> {
+        let len = self.pending.len();
+        let (start, end) = match sep {
+            RecordSep::Char(c) => {
+                return match self.pending[scanned..].find(*c) {
+                    Some(i) => Ok((scanned + i, scanned + i + c.len_utf8())),
+                    None => Err(len),
+                };
+            }
+            RecordSep::Paragraph => {
+                // 読み足した部分の直前の改行と合わせて \n\n になる場合があるので一文字戻る
+                let from = if scanned > self.start && self.pending.as_bytes()[scanned - 1] == b'\n'
+                {
+                    scanned - 1
+                } else {
+                    scanned
+                };
+                let start = match self.pending[from..].find("\n\n") {
+                    Some(i) => from + i,
+                    None => return Err(len),
+                };
+                let end = len - self.pending[start..].trim_start_matches('\n').len();
+                (start, end)
+            }
+            // 正規表現のマッチは読み足した部分より前から始まることがあるので，レコードの先頭から探す
+            RecordSep::Regex(re) => {
+                match re
+                    .find_iter(&self.pending[self.start..])
+                    .find(|m| !m.is_empty())
+                {
+                    Some(m) => (self.start + m.start(), self.start + m.end()),
+                    None => return Err(self.start),
+                }
+            }
+        };
+        if end == len && !self.eof {
+            Err(start)
+        } else {
+            Ok((start, end))
+        }
+    }
+}
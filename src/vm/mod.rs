@@ -1,9 +1,13 @@
+mod ere;
+mod format;
 pub mod ifunc;
+mod io;
 mod value;
-use crate::ast::Value;
+use crate::ast::{GetlineType, RedirectType, Value};
 use crate::ifunc::call_internal_func_from_index;
-use std::collections::HashMap;
+use ere::Regex;
 use rand::prelude::*;
+use std::collections::HashMap;
 
 use std::io::{BufRead, Write};
 
@@ -13,12 +17,15 @@ pub enum Opcode {
     End,
     Push(Value),
     Pop,
+    Dup,
     Jump(usize),
     If(usize),
     NIf(usize),
     Call(usize),
     CallUserFunc(usize),
     Return,
+    ExitCode,
+    Exit(usize),
     // Expression
     Add,
     Sub,
@@ -26,30 +33,126 @@ pub enum Opcode {
     Div,
     Pow,
     Mod,
+    Neg,
+    Plus,
+    Not,
     Cat,
-    And,
-    Or,
     LessThan,
     LessEqualThan,
     NotEqual,
     Equal,
     GreaterThan,
     GreaterEqualThan,
+    Match,
+    NotMatch,
     // AWK
     Readline,
+    NextFile,
+    Getline(GetlineType),
+    GetlineVar(GetlineType),
     Print(usize),
     Printf(usize),
+    Redirect(RedirectType),
     GetField,
+    SetField,
+    LoadRange(usize),
+    SetRange(usize, bool),
     // Variable
-    InitEnv(usize),
+    InitEnv(Vec<String>),
     InitEnvArray(usize),
     LoadVar(usize),
     SetVar(usize),
     LoadArray(usize),
     SetArray(usize),
+    InArray(usize),
+    DeleteArray(usize),
+    ClearArray(usize),
+    FillArray(usize),
+    // For in
+    IterInit(usize),
+    IterNext(usize),
+    IterEnd,
     // For stack frame
     LoadSFVar(usize),
     SetSFVar(usize),
+    // 組み込み変数
+    LoadSpecial(SpecialVar),
+    SetSpecial(SpecialVar),
+    // 条件付きの代入
+    SetIf(SetTarget),
+    // 添字(フィールドの番号)を評価済みの代入
+    SetRef(SetTarget),
+}
+
+// SetIf/SetRefの代入先
+// 配列の添字とフィールドの番号は先に評価されてスタックに積まれている
+#[derive(Debug, PartialEq)]
+pub enum SetTarget {
+    Var(usize),
+    SFVar(usize),
+    Special(SpecialVar),
+    Array(usize),
+    Field,
+}
+
+// 組み込み変数
+// 普通の変数と違い，読み書きするとVMの状態を直接参照，変更する
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SpecialVar {
+    NR,
+    FNR,
+    NF,
+    FS,
+    RS,
+    RT,
+    FILENAME,
+    OFS,
+    ORS,
+    RSTART,
+    RLENGTH,
+    SUBSEP,
+    ARGC,
+    CONVFMT,
+    OFMT,
+}
+
+impl SpecialVar {
+    pub fn from_name(name: &str) -> Option<SpecialVar> {
+        Some(match name {
+            "NR" => SpecialVar::NR,
+            "FNR" => SpecialVar::FNR,
+            "NF" => SpecialVar::NF,
+            "FS" => SpecialVar::FS,
+            "RS" => SpecialVar::RS,
+            "RT" => SpecialVar::RT,
+            "FILENAME" => SpecialVar::FILENAME,
+            "OFS" => SpecialVar::OFS,
+            "ORS" => SpecialVar::ORS,
+            "RSTART" => SpecialVar::RSTART,
+            "RLENGTH" => SpecialVar::RLENGTH,
+            "SUBSEP" => SpecialVar::SUBSEP,
+            "ARGC" => SpecialVar::ARGC,
+            "CONVFMT" => SpecialVar::CONVFMT,
+            "OFMT" => SpecialVar::OFMT,
+            _ => return None,
+        })
+    }
+}
+
+// 組み込みの配列 (envarrayの先頭にこの順で置かれる)
+pub const BUILTIN_ARRAYS: [&str; 3] = ["ARGV", "ENVIRON", "PROCINFO"];
+const ARGV: usize = 0;
+const PROCINFO: usize = 2;
+
+// メインの入力の状態
+enum MainInput {
+    // ARGVから次の入力を探す
+    Next,
+    Stdin,
+    File(io::Input),
+    // 入力はもうない
+    Done,
 }
 
 pub struct VM<'a> {
@@ -58,42 +161,136 @@ pub struct VM<'a> {
     stack: Vec<Value>,
     pc: usize,
 
-    // フィールド
+    // $0 (読み込んだレコードそのもの)
+    record: String,
+    // フィールド ($1, $2, ...)
     fields: Vec<String>,
-    // NF(Number of fields)
-    nf: Value,
+    // NR(Number of records) / FNR(File number of records)
+    nr: usize,
+    fnr: usize,
+    // フィールドセパレータ
+    fs: String,
+    // レコードセパレータ / 最後に読んだレコードの実際のセパレータ
+    rs: String,
+    record_sep: io::RecordSep,
+    rt: String,
+    // メインの入力と標準入力の読み残し
+    main_input: MainInput,
+    main_buf: io::RecordBuf,
+    // 次に調べるARGVの添字 / ファイルを一つでも開いたか
+    argv_index: usize,
+    opened_file: bool,
+    // 現在の入力ファイル名
+    filename: String,
+    // コマンドライン引数の数
+    argc: Value,
+    // 出力フィールドセパレータ / 出力レコードセパレータ
+    ofs: String,
+    ors: String,
+    // match()の結果
+    rstart: Value,
+    rlength: Value,
+    // 多次元配列の添字の区切り
+    subsep: String,
+    // 数値を文字列にするときの書式 / print で出力するときの書式
+    convfmt: String,
+    ofmt: String,
+    // getline < file / cmd | getline で開いたもの
+    inputs: HashMap<String, io::Input>,
+    // print > file / print | cmd で開いたもの
+    outputs: HashMap<String, io::Output>,
+    // 次のprint/printfの出力先 (Noneなら標準出力)
+    redirect: Option<String>,
+    // nextfileで現在の入力の残りを読み飛ばす
+    skip_file: bool,
+    // 終了ステータス
+    exit_code: i32,
+    // 範囲パターンごとの状態 (範囲の中ならtrue)
+    ranges: Vec<bool>,
     // 環境
     env: Vec<Value>,
+    // グローバル変数の名前 (var=value の代入に使う)
+    var_names: Vec<String>,
     envarray: Vec<HashMap<String, Value>>,
     // 戻り先pc 制御スタック
     retpc: Vec<usize>,
     // Stack frame 呼び出しで新しく作られ，returnで消される
     func_env: Vec<Vec<Value>>,
+    // for (key in array) で使う添字の一覧
+    iterators: Vec<ArrayIter>,
     // random
     rng: rand::rngs::StdRng,
+    // コンパイル済みの正規表現
+    regex_cache: HashMap<String, Regex>,
+}
+
+// for (key in array) の状態
+struct ArrayIter {
+    // 配列の番号
+    array: usize,
+    // ループ開始時の添字 (後ろから取り出す)
+    keys: Vec<String>,
+    // 作られたときの関数呼び出しの深さ
+    depth: usize,
 }
 
 // TODO
 // unwrap() などでエラーをハンドリングしているところをきちんと伝搬させるようにする
 
 impl VM<'_> {
-    pub fn new(program: &[Opcode]) -> VM {
+    pub fn new(program: &[Opcode]) -> VM<'_> {
         VM {
             program,
             stack: vec![],
             pc: 0,
 
+            record: String::new(),
             fields: vec![],
-            nf: Value::Num(0.0),
+            nr: 0,
+            fnr: 0,
+            fs: " ".to_string(),
+            rs: "\n".to_string(),
+            record_sep: io::RecordSep::Char('\n'),
+            rt: String::new(),
+            main_input: MainInput::Next,
+            main_buf: io::RecordBuf::default(),
+            argv_index: 1,
+            opened_file: false,
+            filename: String::new(),
+            argc: Value::Num(1.0),
+            ofs: " ".to_string(),
+            ors: "\n".to_string(),
+            rstart: Value::Num(0.0),
+            rlength: Value::Num(-1.0),
+            subsep: "\x1c".to_string(),
+            convfmt: value::DEFAULT_NUMFMT.to_string(),
+            ofmt: value::DEFAULT_NUMFMT.to_string(),
+            inputs: HashMap::new(),
+            outputs: HashMap::new(),
+            redirect: None,
+            skip_file: false,
+            exit_code: 0,
+            ranges: vec![],
             env: vec![],
-            envarray: vec![],
+            var_names: program
+                .iter()
+                .find_map(|op| match op {
+                    Opcode::InitEnv(names) => Some(names.clone()),
+                    _ => None,
+                })
+                .unwrap_or_default(),
+            // ARGVはset_argsで設定する
+            envarray: vec![HashMap::new(), environ(), procinfo()],
             retpc: vec![],
             func_env: vec![],
+            iterators: vec![],
             rng: rand::SeedableRng::from_rng(thread_rng()).unwrap(),
+            regex_cache: HashMap::new(),
         }
     }
 
-    pub fn run<R: BufRead, W: Write>(&mut self, reader: &mut R, writer: &mut W) {
+    // プログラムを実行し，終了ステータスを返す
+    pub fn run<R: BufRead, W: Write>(&mut self, reader: &mut R, writer: &mut W) -> i32 {
         loop {
             match &self.program[self.pc] {
                 Opcode::End => {
@@ -105,6 +302,13 @@ impl VM<'_> {
                 Opcode::Pop => {
                     self.stack.pop();
                 }
+                // スタックのトップを複製する
+                // (一度だけ評価した添字やフィールドの番号を読み書きの両方に使う)
+                Opcode::Dup => {
+                    let top = self.stack.last().unwrap().clone();
+                    self.stack.push(top);
+                }
+
                 Opcode::Jump(pc) => {
                     self.pc = *pc;
                     continue;
@@ -165,9 +369,35 @@ impl VM<'_> {
                     let pc = self.retpc.pop().unwrap();
                     // drpo stack frame
                     self.func_env.pop();
+                    // 関数の中のfor inから抜けた場合
+                    let depth = self.func_env.len();
+                    self.iterators.retain(|it| it.depth <= depth);
                     self.pc = pc;
                 }
 
+                // 終了ステータスを設定する
+                Opcode::ExitCode => {
+                    self.exit_code = self.stack.pop().unwrap().to_float() as i32;
+                }
+                //
+                // Exit
+                // ENDパターンの先頭(pc)にジャンプする
+                // 既にENDパターンを実行中なら終了する
+                // 関数の中では一番外側の呼び出し元の位置で判定する
+                //
+                Opcode::Exit(pc) => {
+                    let current = *self.retpc.first().unwrap_or(&self.pc);
+                    if current >= *pc {
+                        break;
+                    }
+                    self.stack.clear();
+                    self.retpc.clear();
+                    self.func_env.clear();
+                    self.iterators.clear();
+                    self.pc = *pc;
+                    continue;
+                }
+
                 // 四則演算
                 // スタックのトップからR→Lの順に値を取り出し，計算する
                 // トップに置かれた数字が右側なのはコンパイルしやすくするため
@@ -210,50 +440,72 @@ impl VM<'_> {
                     let l = self.stack.pop().unwrap();
                     self.stack.push(l.module(&r));
                 }
-                Opcode::Cat => {
-                    let r = self.stack.pop().unwrap();
-                    let l = self.stack.pop().unwrap();
-                    self.stack.push(l.concat(&r));
+                // 単項演算子
+                // -
+                Opcode::Neg => {
+                    let v = self.stack.pop().unwrap();
+                    self.stack.push(v.minus());
                 }
-                Opcode::And => {
-                    let r = self.stack.pop().unwrap();
-                    let l = self.stack.pop().unwrap();
-                    self.stack.push(l.and(&r));
+                // +
+                Opcode::Plus => {
+                    let v = self.stack.pop().unwrap();
+                    self.stack.push(v.plus());
+                }
+                // !
+                Opcode::Not => {
+                    let v = self.stack.pop().unwrap();
+                    self.stack.push(v.not());
                 }
-                Opcode::Or => {
+                Opcode::Cat => {
                     let r = self.stack.pop().unwrap();
                     let l = self.stack.pop().unwrap();
-                    self.stack.push(l.or(&r));
+                    self.stack.push(l.concat(&r, &self.convfmt));
                 }
                 Opcode::LessThan => {
                     let r = self.stack.pop().unwrap();
                     let l = self.stack.pop().unwrap();
-                    self.stack.push(l.lessthan(&r));
+                    self.stack.push(l.lessthan(&r, &self.convfmt));
                 }
                 Opcode::LessEqualThan => {
                     let r = self.stack.pop().unwrap();
                     let l = self.stack.pop().unwrap();
-                    self.stack.push(l.lessequalthan(&r));
+                    self.stack.push(l.lessequalthan(&r, &self.convfmt));
                 }
                 Opcode::NotEqual => {
                     let r = self.stack.pop().unwrap();
                     let l = self.stack.pop().unwrap();
-                    self.stack.push(l.notequal(&r));
+                    self.stack.push(l.notequal(&r, &self.convfmt));
                 }
                 Opcode::Equal => {
                     let r = self.stack.pop().unwrap();
                     let l = self.stack.pop().unwrap();
-                    self.stack.push(l.equal(&r));
+                    self.stack.push(l.equal(&r, &self.convfmt));
                 }
                 Opcode::GreaterThan => {
                     let r = self.stack.pop().unwrap();
                     let l = self.stack.pop().unwrap();
-                    self.stack.push(l.greaterthan(&r));
+                    self.stack.push(l.greaterthan(&r, &self.convfmt));
                 }
                 Opcode::GreaterEqualThan => {
                     let r = self.stack.pop().unwrap();
                     let l = self.stack.pop().unwrap();
-                    self.stack.push(l.greaterequalthan(&r));
+                    self.stack.push(l.greaterequalthan(&r, &self.convfmt));
+                }
+                //
+                // Match / NotMatch
+                // スタックのトップを正規表現として，その下の値とマッチするか調べる
+                //
+                Opcode::Match => {
+                    let r = self.pop_str();
+                    let l = self.pop_str();
+                    let m = self.regex(&r).is_match(&l);
+                    self.stack.push(Value::Num(if m { 1.0 } else { 0.0 }));
+                }
+                Opcode::NotMatch => {
+                    let r = self.pop_str();
+                    let l = self.pop_str();
+                    let m = self.regex(&r).is_match(&l);
+                    self.stack.push(Value::Num(if m { 0.0 } else { 1.0 }));
                 }
 
                 //
@@ -262,30 +514,126 @@ impl VM<'_> {
                 //  行の読み込みに成功したらスタックに0をpushし，失敗(EOF)したら1をpushする．
                 //
                 Opcode::Readline => op_readline(self, reader),
-                Opcode::Print(n) => op_print(self, writer, *n),
                 //
-                // Printf
-                // 現状，引数の数が一つ以上で，一つ目の引数しか見ない改行のないPrint
-                Opcode::Printf(n) => {
-                    // 引数を捨てる TODO
-                    for _ in 0..*n {
-                        self.stack.pop().unwrap();
+                // NextFile
+                // 現在の入力ファイルの残りを読み飛ばす
+                //
+                Opcode::NextFile => {
+                    self.skip_file = true;
+                }
+                //
+                // Getline(type)
+                // $0を読み込み，結果(1: 成功, 0: EOF, -1: エラー)をスタックに積む
+                // File, Commandの場合はスタックのトップをファイル名(コマンド)として使う
+                //
+                Opcode::Getline(kind) => {
+                    let record = op_getline(self, reader, kind);
+                    match record {
+                        Some(Some(record)) => {
+                            if *kind != GetlineType::File {
+                                self.nr += 1;
+                            }
+                            if *kind == GetlineType::Main {
+                                self.fnr += 1;
+                            }
+                            self.set_record(&record);
+                            self.stack.push(Value::Num(1.0));
+                        }
+                        Some(None) => self.stack.push(Value::Num(0.0)),
+                        None => self.stack.push(Value::Num(-1.0)),
                     }
-                    let s = self.stack.pop().unwrap().to_str();
-                    write!(writer, "{s}",).unwrap();
+                }
+                //
+                // GetlineVar(type)
+                // Getlineと同じだが，$0ではなく変数に読み込む
+                // 変数に代入する値と結果を積む (続くSetIfで成功した場合だけ代入する)
+                //
+                Opcode::GetlineVar(kind) => {
+                    let record = op_getline(self, reader, kind);
+                    match record {
+                        Some(Some(record)) => {
+                            if *kind != GetlineType::File {
+                                self.nr += 1;
+                            }
+                            if *kind == GetlineType::Main {
+                                self.fnr += 1;
+                            }
+                            self.stack.push(Value::StrNum(record));
+                            self.stack.push(Value::Num(1.0));
+                        }
+                        Some(None) => {
+                            self.stack.push(Value::None);
+                            self.stack.push(Value::Num(0.0));
+                        }
+                        None => {
+                            self.stack.push(Value::None);
+                            self.stack.push(Value::Num(-1.0));
+                        }
+                    }
+                }
+                Opcode::Print(n) => {
+                    let line = op_print(self, *n);
+                    self.write_output(writer, &line);
+                }
+                //
+                // Printf(n)
+                // 書式とn個の引数をスタックから取り出し，書式化して出力する
+                //
+                Opcode::Printf(n) => {
+                    let mut args: Vec<Value> = (0..*n).map(|_| self.stack.pop().unwrap()).collect();
+                    args.reverse();
+                    let fmt = self.pop_str();
+                    let s = format::format(&fmt, &args, &self.convfmt);
+                    self.write_output(writer, &s);
+                }
+                //
+                // Redirect(type)
+                // スタックのトップを出力先として開き，次のprint/printfの出力先にする
+                // 一度開いたものはclose()されるまで使い回す (> でも2回目以降は追記になる)
+                //
+                Opcode::Redirect(kind) => {
+                    let name = self.pop_str();
+                    self.open_output(&name, kind);
                 }
                 Opcode::GetField => op_getfield_n(self),
+                //
+                // SetField
+                // スタックのトップをnとし，その下の値を$nに代入する
+                // $0に代入すると分割し直し，それ以外では$0をOFSで組み立て直す
+                // NFより後ろに代入するとその間は空のフィールドになる
+                //
+                Opcode::SetField => {
+                    let n = field_index(self);
+                    let value = self.stack.pop().unwrap();
+                    self.set_field(n, value);
+                }
+                //
+                // LoadRange(n): n番目の範囲パターンの中にいるかをスタックに積む
+                // SetRange(n, b): n番目の範囲パターンの状態を設定する
+                //
+                Opcode::LoadRange(n) => {
+                    let active = self.ranges.get(*n).copied().unwrap_or(false);
+                    self.stack.push(Value::Num(if active { 1.0 } else { 0.0 }));
+                }
+                Opcode::SetRange(n, active) => {
+                    if self.ranges.len() <= *n {
+                        self.ranges.resize(*n + 1, false);
+                    }
+                    self.ranges[*n] = *active;
+                }
 
                 //
                 // 変数
-                //   InitEnv(n): n個分の変数の領域を確保する
+                //   InitEnv(names): 変数の領域を確保する
+                //   InitEnvArray(n): n個分の配列の領域を確保する
                 //   LoadVal(n): n番目の変数の値をスタックのトップに積む
                 //   SetVal(n): スタックトップの値をn番目の変数に設定する
-                Opcode::InitEnv(n) => {
-                    self.env = vec![Value::None; *n];
+                // 実行前に代入された値(-v など)と組み込みの配列は残す
+                Opcode::InitEnv(names) => {
+                    self.env.resize(names.len(), Value::None);
                 }
                 Opcode::InitEnvArray(n) => {
-                    self.envarray = vec![HashMap::new(); *n];
+                    self.envarray.resize(*n, HashMap::new());
                 }
                 Opcode::LoadVar(n) => {
                     self.stack.push(self.env[*n].clone());
@@ -295,20 +643,105 @@ impl VM<'_> {
                     self.env[*n] = val;
                 }
                 // 配列(連想配列)から値を取り出す
+                // 存在しない要素を参照すると空の要素が作られる
+                // (読み込み専用のPROCINFOには作らない)
                 Opcode::LoadArray(n) => {
                     // 二次元配列の取り扱いは想定していない
-                    let index = self.stack.pop().unwrap().to_str();
-                    let val = self.envarray[*n]
-                        .get(&index)
-                        .unwrap_or(&Value::None)
-                        .clone();
+                    let index = self.subscript();
+                    let val = if *n == PROCINFO {
+                        self.envarray[*n]
+                            .get(&index)
+                            .cloned()
+                            .unwrap_or(Value::None)
+                    } else {
+                        self.envarray[*n]
+                            .entry(index)
+                            .or_insert(Value::None)
+                            .clone()
+                    };
                     self.stack.push(val);
                 }
                 Opcode::SetArray(n) => {
-                    let index = self.stack.pop().unwrap().to_str();
+                    let index = self.subscript();
                     let value = self.stack.pop().unwrap();
                     self.envarray[*n].insert(index, value);
                 }
+                // 配列に添字の要素が存在するか調べる
+                // LoadArrayと違い要素を作らない
+                Opcode::InArray(n) => {
+                    let index = self.subscript();
+                    let exists = self.envarray[*n].contains_key(&index);
+                    self.stack.push(Value::Num(if exists { 1.0 } else { 0.0 }));
+                }
+                // 配列から要素を削除する
+                Opcode::DeleteArray(n) => {
+                    let index = self.subscript();
+                    self.envarray[*n].remove(&index);
+                }
+                // 配列の全ての要素を削除する
+                Opcode::ClearArray(n) => {
+                    self.envarray[*n].clear();
+                }
+                // スタックのトップを個数kとし，その下のk個の値を配列の1からkの要素にする
+                // 配列の元の要素は削除する．kはスタックに残す
+                Opcode::FillArray(n) => {
+                    let k = self.stack.pop().unwrap();
+                    let len = k.to_float() as usize;
+                    self.envarray[*n].clear();
+                    for i in (1..=len).rev() {
+                        let value = self.stack.pop().unwrap();
+                        self.envarray[*n].insert(i.to_string(), value);
+                    }
+                    self.stack.push(k);
+                }
+                //
+                // For in
+                //   IterInit(n): n番目の配列の添字の一覧を作る
+                //   IterNext(pc): 次の添字をスタックに積む．残っていなければpcにジャンプ
+                //   IterEnd: 添字の一覧を捨てる
+                //
+                Opcode::IterInit(n) => {
+                    let mut keys: Vec<String> = self.envarray[*n].keys().cloned().collect();
+                    keys.reverse();
+                    self.iterators.push(ArrayIter {
+                        array: *n,
+                        keys,
+                        depth: self.func_env.len(),
+                    });
+                }
+                Opcode::IterNext(pc) => {
+                    let it = self.iterators.last_mut().unwrap();
+                    // ループ中に削除された添字は飛ばす
+                    let key = loop {
+                        match it.keys.pop() {
+                            Some(key) if !self.envarray[it.array].contains_key(&key) => continue,
+                            key => break key,
+                        }
+                    };
+                    match key {
+                        Some(key) => self.stack.push(Value::Str(key)),
+                        None => {
+                            self.pc = *pc;
+                            continue;
+                        }
+                    }
+                }
+                Opcode::IterEnd => {
+                    self.iterators.pop();
+                }
+                //
+                // 組み込み変数
+                //   LoadSpecial(var): 組み込み変数の値をスタックに積む
+                //   SetSpecial(var): スタックのトップの値を組み込み変数に設定する
+                //
+                Opcode::LoadSpecial(var) => {
+                    let value = self.load_special(*var);
+                    self.stack.push(value);
+                }
+                Opcode::SetSpecial(var) => {
+                    let value = self.stack.pop().unwrap();
+                    self.set_special(*var, value);
+                }
                 // 関数ローカル変数のn番目の値をスタックにpush
                 Opcode::LoadSFVar(n) => {
                     let top = self.func_env.last_mut().unwrap();
@@ -320,9 +753,367 @@ impl VM<'_> {
                     let val = self.stack.pop().unwrap();
                     top[*n] = val;
                 }
+                //
+                // SetIf(target)
+                // スタックから結果，値，(添字またはフィールドの番号)を取り出し，
+                // 結果が正の場合だけ値をtargetに代入する．結果はスタックに残す
+                // sub/gsub (置き換えた個数) と getline var (読み込みの結果) で使う
+                //
+                Opcode::SetIf(target) => {
+                    let result = self.stack.pop().unwrap();
+                    let value = self.stack.pop().unwrap();
+                    let store = result.to_float() > 0.0;
+                    self.set_target(target, value, store);
+                    self.stack.push(result);
+                }
+                //
+                // SetRef(target)
+                // スタックから値，(添字またはフィールドの番号)を取り出し，値をtargetに代入する
+                // 代入した値はスタックに残す
+                // ++，--，+= などで使う
+                //
+                Opcode::SetRef(target) => {
+                    let value = self.stack.pop().unwrap();
+                    self.set_target(target, value.clone(), true);
+                    self.stack.push(value);
+                }
             }
             self.pc += 1;
         }
+        // コマンドへの出力より先に標準出力に書いたものを出す
+        writer.flush().unwrap();
+        self.close_all();
+        self.exit_code
+    }
+
+    // SetIf/SetRefの代入先に値を代入する
+    // 配列の添字とフィールドの番号は代入しない場合もスタックから取り除く
+    fn set_target(&mut self, target: &SetTarget, value: Value, store: bool) {
+        match target {
+            SetTarget::Var(n) if store => self.env[*n] = value,
+            SetTarget::SFVar(n) if store => {
+                self.func_env.last_mut().unwrap()[*n] = value;
+            }
+            SetTarget::Special(var) if store => self.set_special(*var, value),
+            SetTarget::Array(n) => {
+                let index = self.subscript();
+                if store {
+                    self.envarray[*n].insert(index, value);
+                }
+            }
+            SetTarget::Field => {
+                let n = field_index(self);
+                if store {
+                    self.set_field(n, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // 致命的なエラーで終了する
+    // それまでの出力が失われないように，標準出力を書き出してファイルやコマンドを閉じる
+    // (mainの標準出力のロックは再入可能なので，ここから書き出せる)
+    fn fatal(&mut self, msg: &str) -> ! {
+        eprintln!("kawk: fatal: {}", msg);
+        let _ = std::io::stdout().flush();
+        self.close_all();
+        std::process::exit(2);
+    }
+
+    // 開いているファイルやコマンドを全て閉じる
+    fn close_all(&mut self) {
+        for (_, input) in self.inputs.drain() {
+            input.close();
+        }
+        for (_, output) in self.outputs.drain() {
+            output.close();
+        }
+    }
+
+    // 名前で指定されたファイルやコマンドを閉じ，終了ステータスを返す
+    // 開かれていなければ-1
+    pub fn close(&mut self, name: &str) -> i32 {
+        if let Some(output) = self.outputs.remove(name) {
+            output.close()
+        } else if let Some(input) = self.inputs.remove(name) {
+            input.close()
+        } else {
+            -1
+        }
+    }
+
+    fn open_output(&mut self, name: &str, kind: &RedirectType) {
+        // 標準出力はそのまま
+        if *kind != RedirectType::Pipe && (name == "/dev/stdout" || name == "-") {
+            self.redirect = None;
+            return;
+        }
+        if !self.outputs.contains_key(name) {
+            let output = match kind {
+                RedirectType::File => io::Output::open_file(name, false),
+                RedirectType::Append => io::Output::open_file(name, true),
+                RedirectType::Pipe => io::Output::open_command(name),
+            };
+            match output {
+                Ok(output) => {
+                    self.outputs.insert(name.to_string(), output);
+                }
+                Err(err) => self.fatal(&format!("can't redirect to `{}': {}", name, err)),
+            }
+        }
+        self.redirect = Some(name.to_string());
+    }
+
+    // print/printfの結果を出力先に書き込む
+    fn write_output<W: Write>(&mut self, writer: &mut W, s: &str) {
+        match self.redirect.take() {
+            Some(name) => {
+                let output = self.outputs.get_mut(&name).unwrap();
+                // 読み手が先に終了したパイプへの書き込みなどは無視する
+                let _ = output.write_all(s.as_bytes());
+            }
+            None => write!(writer, "{}", s).unwrap(),
+        }
+    }
+
+    // $0を設定し，フィールドに分割する
+    // $0は読み込んだままの文字列で，フィールドに代入されるまで組み立て直さない
+    fn set_record(&mut self, record: &str) {
+        let fs = if self.rs.is_empty() && self.fs != " " && !self.fs.is_empty() {
+            // 空行区切りのときは改行もフィールドの区切りになる
+            let mut chars = self.fs.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii_punctuation() => format!("\\{}|\n", c),
+                (Some(c), None) => format!("{}|\n", c),
+                _ => format!("({})|\n", self.fs),
+            }
+        } else {
+            self.fs.clone()
+        };
+        self.fields = self.split(record, &fs);
+        self.record = record.to_string();
+    }
+
+    // スタックトップを文字列として取り出す (数値はCONVFMTで文字列にする)
+    fn pop_str(&mut self) -> String {
+        let value = self.stack.pop().unwrap();
+        value.to_str_fmt(&self.convfmt)
+    }
+
+    // スタックトップを配列の添字にする
+    fn subscript(&mut self) -> String {
+        self.pop_str()
+    }
+
+    // $nに代入する
+    // $0に代入すると分割し直し，それ以外では$0をOFSで組み立て直す
+    fn set_field(&mut self, n: usize, value: Value) {
+        let value = value.to_str_fmt(&self.convfmt);
+        if n == 0 {
+            self.set_record(&value);
+        } else {
+            if self.fields.len() < n {
+                self.fields.resize(n, String::new());
+            }
+            self.fields[n - 1] = value;
+            self.rebuild_record();
+        }
+    }
+
+    // フィールドから$0を組み立て直す
+    fn rebuild_record(&mut self) {
+        self.record = self.fields.join(&self.ofs);
+    }
+
+    fn load_special(&self, var: SpecialVar) -> Value {
+        match var {
+            SpecialVar::NR => Value::Num(self.nr as f64),
+            SpecialVar::FNR => Value::Num(self.fnr as f64),
+            SpecialVar::NF => Value::Num(self.fields.len() as f64),
+            SpecialVar::FS => Value::Str(self.fs.clone()),
+            SpecialVar::RS => Value::Str(self.rs.clone()),
+            SpecialVar::RT => Value::Str(self.rt.clone()),
+            SpecialVar::FILENAME => Value::Str(self.filename.clone()),
+            SpecialVar::OFS => Value::Str(self.ofs.clone()),
+            SpecialVar::ORS => Value::Str(self.ors.clone()),
+            SpecialVar::RSTART => self.rstart.clone(),
+            SpecialVar::RLENGTH => self.rlength.clone(),
+            SpecialVar::SUBSEP => Value::Str(self.subsep.clone()),
+            SpecialVar::ARGC => self.argc.clone(),
+            SpecialVar::CONVFMT => Value::Str(self.convfmt.clone()),
+            SpecialVar::OFMT => Value::Str(self.ofmt.clone()),
+        }
+    }
+
+    fn set_special(&mut self, var: SpecialVar, value: Value) {
+        // 文字列の特殊変数に数値を代入した場合はCONVFMTで文字列にする
+        let text = value.to_str_fmt(&self.convfmt);
+        match var {
+            SpecialVar::NR => self.nr = value.to_float().max(0.0) as usize,
+            SpecialVar::FNR => self.fnr = value.to_float().max(0.0) as usize,
+            // NFを変えるとフィールドが切り詰められるか，空のフィールドが追加される
+            SpecialVar::NF => {
+                let n = value.to_float().max(0.0) as usize;
+                self.fields.resize(n, String::new());
+                self.rebuild_record();
+            }
+            // 次のレコードから使われる
+            SpecialVar::FS => self.fs = text,
+            // "" は空行区切り，一文字はその文字，二文字以上はERE
+            SpecialVar::RS => {
+                self.rs = text;
+                let mut chars = self.rs.chars();
+                self.record_sep = match (chars.next(), chars.next()) {
+                    (None, _) => io::RecordSep::Paragraph,
+                    (Some(c), None) => io::RecordSep::Char(c),
+                    _ => io::RecordSep::Regex(self.regex(&self.rs.clone())),
+                };
+            }
+            SpecialVar::RT => self.rt = text,
+            SpecialVar::FILENAME => self.filename = text,
+            SpecialVar::OFS => self.ofs = text,
+            SpecialVar::ORS => self.ors = text,
+            SpecialVar::RSTART => self.rstart = value,
+            SpecialVar::RLENGTH => self.rlength = value,
+            SpecialVar::SUBSEP => self.subsep = text,
+            SpecialVar::ARGC => self.argc = value,
+            SpecialVar::CONVFMT => self.convfmt = text,
+            SpecialVar::OFMT => self.ofmt = text,
+        }
+    }
+
+    // 名前で指定された変数に値を設定する (-F，var=value など)
+    // プログラムで使われていない変数への代入は無視する
+    pub fn assign(&mut self, name: &str, value: Value) {
+        if let Some(var) = SpecialVar::from_name(name) {
+            self.set_special(var, value);
+        } else if let Some(n) = self.var_names.iter().position(|v| v == name) {
+            if self.env.len() < self.var_names.len() {
+                self.env.resize(self.var_names.len(), Value::None);
+            }
+            self.env[n] = value;
+        }
+    }
+
+    // ARGVとARGCを設定する (ARGV[0]はコマンド名，以降はプログラムの後ろの引数)
+    pub fn set_args(&mut self, args: &[String]) {
+        let argv = &mut self.envarray[ARGV];
+        argv.clear();
+        argv.insert("0".to_string(), Value::Str("kawk".to_string()));
+        for (i, arg) in args.iter().enumerate() {
+            argv.insert((i + 1).to_string(), Value::StrNum(arg.clone()));
+        }
+        self.argc = Value::Num((args.len() + 1) as f64);
+    }
+
+    // ARGVから次の入力を開く
+    //   空文字列や削除された要素は飛ばす
+    //   name=value は変数への代入として扱う
+    //   - は標準入力
+    // ファイルが一つもなければ標準入力を読む
+    fn next_main_input(&mut self) -> MainInput {
+        while (self.argv_index as f64) < self.argc.to_float() {
+            let arg = self.envarray[ARGV]
+                .get(&self.argv_index.to_string())
+                .map(|v| v.to_str_fmt(&self.convfmt))
+                .unwrap_or_default();
+            self.argv_index += 1;
+            if arg.is_empty() {
+                continue;
+            }
+            if let Some((name, value)) = split_assignment(&arg) {
+                self.assign(name, Value::StrNum(crate::parser::unescape(value)));
+                continue;
+            }
+            self.opened_file = true;
+            let input = if arg == "-" {
+                MainInput::Stdin
+            } else {
+                match io::Input::open_file(&arg) {
+                    Ok(input) => MainInput::File(input),
+                    Err(err) => {
+                        eprintln!(
+                            "kawk: warning: cannot open file `{}' for reading: {}",
+                            arg, err
+                        );
+                        self.exit_code = 2;
+                        continue;
+                    }
+                }
+            };
+            self.filename = arg;
+            self.fnr = 0;
+            return input;
+        }
+        if self.opened_file {
+            MainInput::Done
+        } else {
+            self.opened_file = true;
+            MainInput::Stdin
+        }
+    }
+
+    // 文字列をフィールドセパレータfsで分割する
+    //   " ": 空白，タブ，改行の並びで区切り，前後の空白は無視する
+    //   "": 一文字ずつに分ける
+    //   それ以外の一文字: その文字で区切る
+    //   それ以外: EREとして区切る
+    pub fn split(&mut self, s: &str, fs: &str) -> Vec<String> {
+        if s.is_empty() {
+            return vec![];
+        }
+        if fs == " " {
+            return s
+                .split([' ', '\t', '\n'])
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+                .collect();
+        }
+        let mut chars = fs.chars();
+        match (chars.next(), chars.next()) {
+            (None, _) => s.chars().map(|c| c.to_string()).collect(),
+            (Some(c), None) if c != '\\' => s.split(c).map(|f| f.to_string()).collect(),
+            _ => self.split_regex(s, fs),
+        }
+    }
+
+    // 文字列をEREで分割する
+    // split()の第3引数が正規表現リテラルの場合は一文字でもこちらを使う
+    pub fn split_regex(&mut self, s: &str, ere: &str) -> Vec<String> {
+        if s.is_empty() {
+            return vec![];
+        }
+        self.regex(ere)
+            .split(s)
+            .into_iter()
+            .map(|f| f.to_string())
+            .collect()
+    }
+
+    // EREをコンパイルする．一度コンパイルしたものは使い回す
+    pub fn regex(&mut self, ere: &str) -> Regex {
+        if let Some(re) = self.regex_cache.get(ere) {
+            return re.clone();
+        }
+        let re = match ere::compile(ere) {
+            Ok(re) => re,
+            Err(err) => self.fatal(&format!("invalid regexp `{}': {}", ere, err)),
+        };
+        self.regex_cache.insert(ere.to_string(), re.clone());
+        re
+    }
+
+    // 現在のメインの入力を閉じる
+    fn finish_main_input(&mut self) {
+        match std::mem::replace(&mut self.main_input, MainInput::Next) {
+            MainInput::File(input) => {
+                input.close();
+            }
+            MainInput::Stdin => self.main_buf = io::RecordBuf::default(),
+            MainInput::Done => self.main_input = MainInput::Done,
+            MainInput::Next => {}
+        }
     }
 
     pub fn show_stack_and_env(self) {
@@ -334,10 +1125,12 @@ impl VM<'_> {
 }
 
 fn op_readline<R: BufRead>(vm: &mut VM, reader: &mut R) {
-    let mut line = String::new();
-    if reader.read_line(&mut line).expect("Failed to read line.") != 0 {
-        vm.fields = line.split_whitespace().map(|f| f.to_string()).collect();
-        vm.nf = Value::Num(vm.fields.len() as f64);
+    // next/nextfileでループから抜けてきた場合
+    vm.iterators.clear();
+    if let Some(record) = read_main_record(vm, reader) {
+        vm.nr += 1;
+        vm.fnr += 1;
+        vm.set_record(&record);
         vm.stack.push(Value::Num(0.0));
     } else {
         // 読む行がなくなったとき
@@ -345,35 +1138,169 @@ fn op_readline<R: BufRead>(vm: &mut VM, reader: &mut R) {
     }
 }
 
-fn op_print<W: Write>(vm: &mut VM, writer: &mut W, n: usize) {
-    let mut s = false;
+// メインの入力からレコードを一つ読み込む
+// 入力の終わりに達したらARGVの次の入力に移る
+fn read_main_record<R: BufRead>(vm: &mut VM, reader: &mut R) -> Option<String> {
+    loop {
+        // nextfileの後は現在の入力を閉じて次に進む
+        if vm.skip_file {
+            vm.skip_file = false;
+            vm.finish_main_input();
+        }
+        let read = match &mut vm.main_input {
+            MainInput::Next => {
+                vm.main_input = vm.next_main_input();
+                continue;
+            }
+            MainInput::Done => return None,
+            MainInput::Stdin => vm.main_buf.read_record(reader, &vm.record_sep),
+            MainInput::File(input) => input.read_record(&vm.record_sep),
+        };
+        match read {
+            Ok(Some((record, rt))) => {
+                vm.rt = rt;
+                return Some(record);
+            }
+            Ok(None) => vm.finish_main_input(),
+            Err(err) => {
+                eprintln!("kawk: error reading input file `{}': {}", vm.filename, err);
+                vm.exit_code = 2;
+                vm.finish_main_input();
+            }
+        }
+    }
+}
+
+// ENVIRONの初期値 (プロセスの環境変数)
+fn environ() -> HashMap<String, Value> {
+    std::env::vars_os()
+        .map(|(k, v)| {
+            (
+                k.to_string_lossy().into_owned(),
+                Value::StrNum(v.to_string_lossy().into_owned()),
+            )
+        })
+        .collect()
+}
+
+// PROCINFOの値 (読み込み専用)
+fn procinfo() -> HashMap<String, Value> {
+    let mut info = HashMap::new();
+    let mut set = |key: &str, value: Value| info.insert(key.to_string(), value);
+    set("pid", Value::Num(std::process::id() as f64));
+    #[cfg(unix)]
+    {
+        set(
+            "ppid",
+            Value::Num(std::os::unix::process::parent_id() as f64),
+        );
+        // getuid等は失敗しない
+        let (uid, euid, gid, egid) = unsafe {
+            (
+                libc::getuid(),
+                libc::geteuid(),
+                libc::getgid(),
+                libc::getegid(),
+            )
+        };
+        set("uid", Value::Num(uid as f64));
+        set("euid", Value::Num(euid as f64));
+        set("gid", Value::Num(gid as f64));
+        set("egid", Value::Num(egid as f64));
+    }
+    set("version", Value::Str(env!("CARGO_PKG_VERSION").to_string()));
+    // フィールドの分割はFSによるものだけ
+    set("FS", Value::Str("FS".to_string()));
+    set(
+        "platform",
+        Value::Str(
+            if cfg!(unix) {
+                "posix"
+            } else {
+                std::env::consts::OS
+            }
+            .to_string(),
+        ),
+    );
+    info
+}
+
+// name=value の形ならnameとvalueに分ける
+pub fn split_assignment(arg: &str) -> Option<(&str, &str)> {
+    let (name, value) = arg.split_once('=')?;
+    let mut chars = name.chars();
+    let head = chars.next()?;
+    if (head.is_ascii_alphabetic() || head == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        Some((name, value))
+    } else {
+        None
+    }
+}
+
+// getlineの読み込み先からレコードを一つ読み込む
+// 読み込めたらSome(Some(record))，EOFならSome(None)，エラーならNone
+fn op_getline<R: BufRead>(
+    vm: &mut VM,
+    reader: &mut R,
+    kind: &GetlineType,
+) -> Option<Option<String>> {
+    match kind {
+        GetlineType::Main => Some(read_main_record(vm, reader)),
+        GetlineType::File | GetlineType::Command => {
+            let name = vm.pop_str();
+            if !vm.inputs.contains_key(&name) {
+                let input = if *kind == GetlineType::File {
+                    io::Input::open_file(&name)
+                } else {
+                    io::Input::open_command(&name)
+                };
+                vm.inputs.insert(name.to_string(), input.ok()?);
+            }
+            let input = vm.inputs.get_mut(&name).unwrap();
+            let (record, rt) = match input.read_record(&vm.record_sep).ok()? {
+                Some(record) => record,
+                None => return Some(None),
+            };
+            vm.rt = rt;
+            Some(Some(record))
+        }
+    }
+}
+
+// print文で出力する文字列を作る
+fn op_print(vm: &mut VM, n: usize) -> String {
     let mut i = vec![];
     for _ in 0..n {
-        i.push(vm.stack.pop().unwrap().to_str());
-    }
-    for o in i.iter().rev() {
-        write!(
-            writer,
-            "{}{}",
-            if s { " " } else { "" },
-            // スタックが空の時はpanicする
-            o
-        )
-        .unwrap();
-        s = true;
-    }
-    writeln!(writer).unwrap();
+        // スタックが空の時はpanicする
+        // 数値はOFMTで出力する
+        i.push(vm.stack.pop().unwrap().to_str_fmt(&vm.ofmt));
+    }
+    i.reverse();
+    let mut line = i.join(&vm.ofs);
+    line.push_str(&vm.ors);
+    line
+}
+
+// スタックトップからフィールドの番号を取り出す
+fn field_index(vm: &mut VM) -> usize {
+    let n = vm.stack.pop().unwrap().to_float();
+    if n < 0.0 {
+        vm.fatal(&format!("attempt to access field {}", n));
+    }
+    n as usize
 }
 
 // スタックトップの値をnとし，$nの値を取得し，スタックのトップに配置する
 fn op_getfield_n(vm: &mut VM) {
-    let n = vm.stack.pop().unwrap().to_float() as usize;
+    let n = field_index(vm);
     if n == 0 {
-        vm.stack.push(Value::Str(vm.fields.join(" ")));
+        vm.stack.push(Value::StrNum(vm.record.clone()));
     } else if n <= vm.fields.len() {
-        vm.stack.push(Value::Str(vm.fields[n - 1].clone()));
+        vm.stack.push(Value::StrNum(vm.fields[n - 1].clone()));
     } else {
-        vm.stack.push(Value::Str("".to_string()));
+        vm.stack.push(Value::StrNum("".to_string()));
     }
 }
 
@@ -430,7 +1357,7 @@ fn test_vm3() {
     use std::str;
 
     let prg = [
-        Opcode::InitEnv(1),
+        Opcode::InitEnv(vec!["a".to_string()]),
         Opcode::Push(Value::Num(44.0)),
         Opcode::SetVar(0),
         Opcode::LoadVar(0),
@@ -1,49 +1,127 @@
+use super::format;
 use crate::ast::Value;
-use crate::parser::awk::number;
+use std::cmp::Ordering;
 
 // Value
 // AWKの値を管理する
 // 新規作成
 //   Value::Num(f64)
 //   Value::Str(String)
+//   Value::StrNum(String): フィールド，getline，ARGV，ENVIRON，-v などの入力から来た文字列
 //   Value::None
 //
-// val.to_str(): 文字列化
+// val.to_str_fmt(fmt): 文字列化 (整数でない数値はfmt (CONVFMTかOFMT) で書式化する)
 // val.to_float(): 数値化
 // val.is_true(): 真偽判定
+// val.as_number(): 数値として扱う場合はSome(数値)
 //
 // 仕様はPOSIXに由来します
 
+// CONVFMT，OFMTの初期値
+pub const DEFAULT_NUMFMT: &str = "%.6g";
+
 impl Value {
     // Value -> f64 / String
-    pub fn to_str(&self) -> String {
+    // 整数の値は整数として，それ以外はfmt (CONVFMTかOFMT) で文字列にする
+    pub fn to_str_fmt(&self, fmt: &str) -> String {
         match self {
-            Value::Num(n) => n.to_string(),
-            Value::Str(s) => s.clone(),
+            Value::Num(n) if *n == n.trunc() && n.abs() < i64::MAX as f64 => {
+                (*n as i64).to_string()
+            }
+            Value::Num(n) => format::format(fmt, &[Value::Num(*n)], DEFAULT_NUMFMT),
+            Value::Str(s) | Value::StrNum(s) => s.clone(),
             Value::None => "".to_string(),
         }
     }
     pub fn to_dbgstr(&self) -> String {
         match self {
             Value::Num(n) => n.to_string(),
-            Value::Str(s) => format!("\"{}\"", s),
+            Value::Str(s) | Value::StrNum(s) => format!("\"{}\"", s),
             Value::None => "None".to_string(),
         }
     }
     pub fn to_float(&self) -> f64 {
         match self {
             Value::Num(n) => *n,
-            Value::Str(s) => number(s).unwrap_or(0.0),
+            Value::Str(s) | Value::StrNum(s) => strtod(s).0,
             Value::None => 0.0,
         }
     }
+    // 真偽判定のルール
+    //   数値 (数値に見えるStrNumを含む) -> 0以外なら真
+    //   文字列 -> 空でなければ真 ("0" も真)
+    //   未初期化の値 -> 偽
     pub fn is_true(&self) -> bool {
         match self {
-            Value::Num(n) => *n == 1.0,
+            Value::Num(n) => *n != 0.0,
             Value::Str(s) => !s.is_empty(),
+            Value::StrNum(s) => match looks_numeric(s) {
+                Some(n) => n != 0.0,
+                None => !s.is_empty(),
+            },
             Value::None => false,
         }
     }
+    // 数値，または数値に見えるStrNumなら数値を返す
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Num(n) => Some(*n),
+            Value::StrNum(s) => looks_numeric(s),
+            _ => None,
+        }
+    }
+}
+
+// 文字列の先頭から数値として読める部分を読む (Cのstrtodと同じ)
+// 数値とその終わりの位置を返す．読めなければ(0.0, 0)
+fn strtod(s: &str) -> (f64, usize) {
+    let b = s.as_bytes();
+    let mut i = 0;
+    while i < b.len() && matches!(b[i], b' ' | b'\t' | b'\n') {
+        i += 1;
+    }
+    let start = i;
+    if i < b.len() && matches!(b[i], b'+' | b'-') {
+        i += 1;
+    }
+    let int_digits = count_digits(&b[i..]);
+    i += int_digits;
+    let mut frac_digits = 0;
+    if i < b.len() && b[i] == b'.' {
+        frac_digits = count_digits(&b[i + 1..]);
+        if int_digits > 0 || frac_digits > 0 {
+            i += 1 + frac_digits;
+        }
+    }
+    if int_digits == 0 && frac_digits == 0 {
+        return (0.0, 0);
+    }
+    // 指数部 (数字が続かなければ読まない)
+    if i < b.len() && matches!(b[i], b'e' | b'E') {
+        let mut j = i + 1;
+        if j < b.len() && matches!(b[j], b'+' | b'-') {
+            j += 1;
+        }
+        let exp_digits = count_digits(&b[j..]);
+        if exp_digits > 0 {
+            i = j + exp_digits;
+        }
+    }
+    (s[start..i].parse().unwrap_or(0.0), i)
+}
+
+fn count_digits(b: &[u8]) -> usize {
+    b.iter().take_while(|c| c.is_ascii_digit()).count()
+}
+
+// 前後の空白を除いて全体が数値として読めるか
+fn looks_numeric(s: &str) -> Option<f64> {
+    let (n, end) = strtod(s);
+    if end > 0 && s[end..].chars().all(|c| matches!(c, ' ' | '\t' | '\n')) {
+        Some(n)
+    } else {
+        None
+    }
 }
 
 #[allow(clippy::upper_case_acronyms)]
@@ -77,94 +155,193 @@ impl Value {
     pub fn pow(&self, val: &Value) -> Value {
         Value::Num(self.to_float().powf(val.to_float()))
     }
-    #[allow(dead_code)]
     pub fn not(&self) -> Value {
         Value::Num(if self.is_true() { 0.0 } else { 1.0 })
     }
-    #[allow(dead_code)]
     pub fn plus(&self) -> Value {
         Value::Num(self.to_float())
     }
-    #[allow(dead_code)]
     pub fn minus(&self) -> Value {
-        Value::Num(self.to_float() * -1.0)
+        Value::Num(-self.to_float())
     }
-    pub fn concat(&self, val: &Value) -> Value {
-        Value::Str(self.to_str() + &val.to_str())
-    }
-    pub fn and(&self, val: &Value) -> Value {
-        // 短絡評価
-        if !self.is_true() {
-            return Value::Num(0.0);
-        };
-        if !val.is_true() {
-            return Value::Num(0.0);
-        };
-        Value::Num(1.0)
-    }
-    pub fn or(&self, val: &Value) -> Value {
-        // 短絡評価
-        if self.is_true() {
-            return Value::Num(1.0);
-        };
-        if val.is_true() {
-            return Value::Num(1.0);
-        };
-        Value::Num(0.0)
+    pub fn concat(&self, val: &Value, convfmt: &str) -> Value {
+        Value::Str(self.to_str_fmt(convfmt) + &val.to_str_fmt(convfmt))
     }
     // 比較のルール
-    // 両方が数字(または未初期化の値) -> 数値として比較する
-    // それ以外 -> 文字列に変換して比較する
-    // POSIXの記述は誤りです
+    //   両方が数値として扱える -> 数値として比較する
+    //     (数値，数値に見えるStrNum，未初期化の値)
+    //   それ以外 -> 文字列として比較する
+    //     (数値はconvfmtで文字列にし，未初期化の値は "" とする)
+    //   NaNとの数値の比較は != だけが真
     //
-    fn compbase(&self, val: &Value, op: Operator) -> Value {
-        let left = if matches!(self, Value::None) { &Value::Num(0.0) } else { self };
-        let right = if matches!(val, Value::None) { &Value::Num(0.0) } else { val };
-        Value::Num(
-            if match (left, right) {
-                (Value::Num(left), Value::Num(right)) => match op {
-                    Operator::LT => left < right,
-                    Operator::LET => left <= right,
-                    Operator::NE => left != right,
-                    Operator::EQ => left == right,
-                    Operator::GT => left > right,
-                    Operator::GET => left <= right,
-                },
-                (_, _) => {
-                    let (left, right) = (left.to_str(), right.to_str());
-                    match op {
-                        Operator::LT => left < right,
-                        Operator::LET => left <= right,
-                        Operator::NE => left != right,
-                        Operator::EQ => left == right,
-                        Operator::GT => left > right,
-                        Operator::GET => left <= right,
-                    }
-                }
-            } {
-                1.0
-            } else {
-                0.0
+    fn compbase(&self, val: &Value, op: Operator, convfmt: &str) -> Value {
+        let numeric = |v: &Value| match v {
+            Value::None => Some(0.0),
+            v => v.as_number(),
+        };
+        let ord = match (numeric(self), numeric(val)) {
+            (Some(left), Some(right)) => left.partial_cmp(&right),
+            // 文字列として比較するときは元の文字列を使う
+            (_, _) => Some(self.to_str_fmt(convfmt).cmp(&val.to_str_fmt(convfmt))),
+        };
+        let result = match ord {
+            None => matches!(op, Operator::NE),
+            Some(ord) => match op {
+                Operator::LT => ord == Ordering::Less,
+                Operator::LET => ord != Ordering::Greater,
+                Operator::NE => ord != Ordering::Equal,
+                Operator::EQ => ord == Ordering::Equal,
+                Operator::GT => ord == Ordering::Greater,
+                Operator::GET => ord != Ordering::Less,
             },
-        )
+        };
+        Value::Num(if result { 1.0 } else { 0.0 })
     }
     // <
-    pub fn lessthan(&self, val: &Value) -> Value {
-        self.compbase(val, Operator::LT)
+    pub fn lessthan(&self, val: &Value, convfmt: &str) -> Value {
+        self.compbase(val, Operator::LT, convfmt)
     }
-    pub fn lessequalthan(&self, val: &Value) -> Value {
-        self.compbase(val, Operator::LET)
+    pub fn lessequalthan(&self, val: &Value, convfmt: &str) -> Value {
+        self.compbase(val, Operator::LET, convfmt)
     }
-    pub fn notequal(&self, val: &Value) -> Value {
-        self.compbase(val, Operator::NE)
+    pub fn notequal(&self, val: &Value, convfmt: &str) -> Value {
+        self.compbase(val, Operator::NE, convfmt)
     }
-    pub fn equal(&self, val: &Value) -> Value {
-        self.compbase(val, Operator::EQ)
+    pub fn equal(&self, val: &Value, convfmt: &str) -> Value {
+        self.compbase(val, Operator::EQ, convfmt)
     }
-    pub fn greaterthan(&self, val: &Value) -> Value {
-        self.compbase(val, Operator::GT)
+    pub fn greaterthan(&self, val: &Value, convfmt: &str) -> Value {
+        self.compbase(val, Operator::GT, convfmt)
     }
-    pub fn greaterequalthan(&self, val: &Value) -> Value {
-        self.compbase(val, Operator::GET)
+    pub fn greaterequalthan(&self, val: &Value, convfmt: &str) -> Value {
+        self.compbase(val, Operator::GET, convfmt)
+    }
+}
+
+#[test]
+fn test_strnum() {
+    let set = [
+        ("10", Some(10.0), 10.0),
+        (" 1e2 ", Some(100.0), 100.0),
+        ("+.5", Some(0.5), 0.5),
+        ("-3.", Some(-3.0), -3.0),
+        ("1e", None, 1.0),
+        ("3abc", None, 3.0),
+        (".", None, 0.0),
+        ("", None, 0.0),
+        ("abc", None, 0.0),
+        ("0x10", None, 0.0),
+    ];
+    for (s, numeric, float) in set {
+        let v = Value::StrNum(s.to_string());
+        assert_eq!(numeric, v.as_number(), "{:?}", s);
+        assert_eq!(float, v.to_float(), "{:?}", s);
+        assert_eq!(None, Value::Str(s.to_string()).as_number(), "{:?}", s);
+    }
+}
+
+#[test]
+fn test_to_str() {
+    let set = [
+        (1.0, "%.6g", "1"),
+        (-42.0, "%.6g", "-42"),
+        (1e18, "%.6g", "1000000000000000000"),
+        (1e21, "%.6g", "1e+21"),
+        (0.1 + 0.2, "%.6g", "0.3"),
+        (1.0 / 3.0, "%.6g", "0.333333"),
+        (1.0 / 3.0, "%.2f", "0.33"),
+        (123456789.5, "%.6g", "1.23457e+08"),
+        (f64::INFINITY, "%.6g", "inf"),
+    ];
+    for (n, fmt, expect) in set {
+        assert_eq!(expect, Value::Num(n).to_str_fmt(fmt), "{} {}", n, fmt);
+    }
+}
+
+#[test]
+fn test_is_true() {
+    let set = [
+        (Value::Num(0.0), false),
+        (Value::Num(1.0), true),
+        (Value::Num(2.0), true),
+        (Value::Num(-1.0), true),
+        (Value::Num(0.5), true),
+        (Value::Str("".to_string()), false),
+        (Value::Str("0".to_string()), true),
+        (Value::Str("a".to_string()), true),
+        (Value::StrNum("".to_string()), false),
+        (Value::StrNum("0".to_string()), false),
+        (Value::StrNum(" 0.0 ".to_string()), false),
+        (Value::StrNum("5".to_string()), true),
+        (Value::StrNum("0x".to_string()), true),
+        (Value::StrNum(" ".to_string()), true),
+        (Value::None, false),
+    ];
+    for (v, expect) in set {
+        assert_eq!(expect, v.is_true(), "{:?}", v);
+        assert_eq!(
+            Value::Num(if expect { 0.0 } else { 1.0 }),
+            v.not(),
+            "{:?}",
+            v
+        );
+    }
+}
+
+#[test]
+fn test_compare() {
+    let num = |n: f64| Value::Num(n);
+    let str = |s: &str| Value::Str(s.to_string());
+    let strnum = |s: &str| Value::StrNum(s.to_string());
+    // [<, <=, !=, ==, >, >=]
+    let less = [1, 1, 1, 0, 0, 0];
+    let equal = [0, 1, 0, 1, 0, 1];
+    let greater = [0, 0, 1, 0, 1, 1];
+    let set = [
+        // 数値同士
+        (num(1.0), num(2.0), less),
+        (num(2.0), num(2.0), equal),
+        (num(10.0), num(9.0), greater),
+        (num(f64::NAN), num(1.0), [0, 0, 1, 0, 0, 0]),
+        // 文字列同士
+        (str("10"), str("9"), less),
+        (str("abc"), str("abc"), equal),
+        (str("b"), str("a"), greater),
+        // 数値と文字列は文字列として比較する
+        (num(10.0), str("9"), less),
+        (num(0.1 + 0.2), str("0.3"), equal),
+        (str("1.0"), num(1.0), greater),
+        // 数値に見えるStrNumは数値として比較する
+        (strnum("10"), strnum("9"), greater),
+        (strnum("10"), num(9.0), greater),
+        (strnum(" 1e1 "), num(10.0), equal),
+        (num(-1.0), strnum("-0.5"), less),
+        // StrNumと文字列は文字列として比較する
+        (strnum("10"), str("10.0"), less),
+        (strnum("10.0"), str("10.0"), equal),
+        // 数値に見えないStrNumは文字列
+        (strnum("abc"), num(1.0), greater),
+        (strnum("1x"), strnum("9"), less),
+        // 未初期化の値は 0 とも "" とも等しい
+        (Value::None, num(0.0), equal),
+        (Value::None, str(""), equal),
+        (Value::None, strnum(""), equal),
+        (Value::None, Value::None, equal),
+        (Value::None, num(-1.0), greater),
+        (Value::None, strnum("0.5"), less),
+        (Value::None, str("0"), less),
+        (str("a"), Value::None, greater),
+    ];
+    for (left, right, expect) in set {
+        let result = [
+            left.lessthan(&right, DEFAULT_NUMFMT),
+            left.lessequalthan(&right, DEFAULT_NUMFMT),
+            left.notequal(&right, DEFAULT_NUMFMT),
+            left.equal(&right, DEFAULT_NUMFMT),
+            left.greaterthan(&right, DEFAULT_NUMFMT),
+            left.greaterequalthan(&right, DEFAULT_NUMFMT),
+        ];
+        let expect = expect.map(|b| Value::Num(b as f64));
+        assert_eq!(expect, result, "{:?} {:?}", left, right);
     }
 }
@@ -23,8 +23,7 @@ fn test_my_command() {
             "",
             "55\n",
         ],
-        // TODO: 単項演算子を実装したら
-        // ["BEGIN{printf 1 (-2)}", "", "1-2"],
+        ["BEGIN{printf 1 (-2)}", "", "1-2"],
         ["BEGIN{printf 1-2}", "", "-1"],
         // 引数のない関数呼び出し
         [
@@ -170,24 +169,317 @@ fn test_my_command() {
         ],
         ["BEGIN{printf 123; print 123}", "", "123123\n"],
         ["BEGIN{printf 0 == i}", "", "1"],
-        ["BEGIN{printf 1+2}", "", "3"]
-        // [
-                                                          //     "
-                                                          //     BEGIN {
-                                                          //       for (i=0; i<10;) {
-                                                          //         print i
-                                                          //         i += 1
-                                                          //       }
-                                                          //     }
-                                                          //     ",
-                                                          //     "",
-                                                          //     "0\n1\n2\n3\n4\n5\n6\n7\n8\n9\n"
-                                                          // ],
-                                                          // [
-                                                          //     "",
-                                                          //     "",
-                                                          //     ""
-                                                          // ],
+        ["BEGIN{printf 1+2}", "", "3"],
+        // 組み込み変数
+        [
+            "NR==1 {next} {print NR \": \" $0, NF, FNR} END{print NR, NF}",
+            "h1 h2\na b c\nd e\n",
+            "2: a b c 3 2\n3: d e 2 3\n3 2\n",
+        ],
+        [
+            "BEGIN{OFS = \"-\"; ORS = \"|\\n\"; print \"a\", \"b\"; print \"[\" FILENAME \"]\"}",
+            "",
+            "a-b|\n[]|\n",
+        ],
+        ["{NF = 2; print; NF = 4; print $0 \"|\"; NR = 10} END{print NR}", "a b c\n", "a b\na b  |\n10\n"],
+        [
+            "BEGIN{SUBSEP = \":\"; a[1, 2]; for (k in a) print k; print match(\"abc\", /c/), RSTART, RLENGTH}",
+            "",
+            "1:2\n3 3 1\n",
+        ],
+        // $0 は読み込んだまま
+        ["{print; print $0 \"|\"; print $1}", "  a  b\tc \n", "  a  b\tc \n  a  b\tc |\na\n"],
+        ["/b/ {OFS = \":\"; print; $1 = $1; print}", "x\n a   b \n", " a   b \na:b\n"],
+        // FS
+        ["BEGIN{FS = \":\"} {print $1, $3, NF}", "root:x:0:0\n", "root 0 4\n"],
+        ["BEGIN{FS = \"[0-9]+\"} {print $3, NF}", "a1b22c\n", "c 3\n"],
+        ["BEGIN{FS = \"\\t\"} {print $2}", "a\tb c\td\n", "b c\n"],
+        ["NR == 1 {FS = \",\"; print $2} NR == 2 {print $2}", "a,b\nc,d\n", "\nd\n"],
+        ["BEGIN{FS = \".\"} {print $2, split(\"x.y.z\", a)}", "a.b\n", "b 3\n"],
+        // 空のマッチは区切りにならない
+        ["BEGIN{FS = \"a*\"} {print NF, $1, $2}", "xaay\n", "2 x y\n"],
+        // RS
+        ["BEGIN{RS = \";\"} {print NR \": \" $0}", "a;b;c", "1: a\n2: b\n3: c\n"],
+        [
+            "BEGIN{RS = \"\"} {print NR, NF, $1, length(RT)}",
+            "\n\nname: a\nx: 1\n\n\n\nname: b\nx: 2\n\n",
+            "1 4 name: 4\n2 4 name: 2\n",
+        ],
+        ["BEGIN{RS = \"\"; FS = \":\"} {print NF, $2, $3}", "name:a\nx:1\n\nname:b\n", "4 a x\n2 b \n"],
+        ["BEGIN{RS = \"[0-9]+\"} {print $0 \"<\" RT \">\"}", "a12b345c", "a<12>\nb<345>\nc<>\n"],
+        ["{print $0 \"<\" RT \">\"}", "l1\nl2", "l1<\n>\nl2<>\n"],
+        // 入力から来た数値に見える文字列は数値として比較する
+        ["$3 > 100 {print $1}", "a x 150\nb y 99\nc z abc\n", "a\nc\n"],
+        ["{print ($1 == 10), ($1 == \"10\"), ($1 < $2)}", "10.0 9\n 1e1 x\n", "1 0 0\n1 0 1\n"],
+        ["{n = split($0, a, \",\"); print (a[1] < a[2])}", "9,10\n", "1\n"],
+        ["!$1 {print \"zero\"}", "0\n0.0\nx\n", "zero\nzero\n"],
+        ["{printf \"%c\\n\", $1}", "65\n", "A\n"],
+        ["{print $1 + 0, $2 * 2}", "3abc .5e1x\n", "3 10\n"],
+        // 真偽値と比較
+        ["BEGIN{if (2) print \"a\"; n = 3; while (n) n--; print n, (2 >= 1), (1 >= 2), !\"0\", !x}", "", "a\n0 1 0 0 1\n"],
+        ["$1 >= 10 && $2", "10 0\n10 x\n9 1\n", "10 x\n"],
+        // && と || は短絡評価する
+        ["BEGIN{x = 0; print (x != 0 && 10 / x > 1), (x == 0 || 10 / x > 1)}", "", "0 1\n"],
+        ["function f(s) {printf s; return s} BEGIN{print (f(0) && f(1)), (f(1) || f(2)), (f(1) && f(0) || f(3))}", "", "011030 1 1\n"],
+        ["$2 && $1 / $2 > 1 {print \"a\"} !$2 || $1 > 4 {print \"b\"}", "5 0\n6 2\n", "b\na\nb\n"],
+        // 数値の文字列化 (整数はそのまま，それ以外はCONVFMT/OFMT)
+        ["BEGIN{print 1e21, 0.1 + 0.2, 2.5e-3, 1E2, .5, 3., 100 / 3}", "", "1e+21 0.3 0.0025 100 0.5 3 33.3333\n"],
+        ["BEGIN{CONVFMT = \"%.2f\"; x = 3.14159 \"\"; a[0.5] = 1; for (k in a) print x, k}", "", "3.14 0.50\n"],
+        [
+            "{CONVFMT = \"%.2g\"; x = 3.14159; print (x == \"3.1\"), (x < \"3.2\"), length(x); $2 = x; $3 = 0.5; print; print $0 == \"a 3.1 0.5\"}",
+            "a b\n",
+            "1 1 3\na 3.1 0.5\n1\n",
+        ],
+        ["BEGIN{OFMT = \"%.2g\"; x = 3.14159; print x, x \"\", 10}", "", "3.1 3.14159 10\n"],
+        // フィールドへの代入
+        ["{$2 = \"X\"; print; print NF}", "a b c\n", "a X c\n3\n"],
+        [
+            "{OFS = \"-\"; $5 = \"z\"; print; print NF; $0 = \"p q\"; print $2, NF; NF = 3; print}",
+            "a b c\n",
+            "a-b-c--z\n5\nq-2\np-q-\n",
+        ],
+        ["{$1++; ++$2; $(1+1) += 10; i = 1; $i = $i \"!\"; print; print $NF-1}", "3 4\n", "4! 15\n14\n"],
+        // フィールドの番号や添字は一度だけ評価する
+        ["{i = 1; $(i++)++; print; print i; $(i++) += 1; print; print i}", "a 5 7\n", "1 5 7\n2\n1 6 7\n3\n"],
+        ["BEGIN{a[j++] += 2; a[j--]--; print j, a[0], a[1]}", "", "0 2 -1\n"],
+        ["{sub(/b/, \"B\", $2); gsub(/a/, \"A\"); print; print $2}", "a ab\n", "A AB\nAB\n"],
+        // 文字列関数
+        [
+            "BEGIN{print substr(\"hello\", 2, 3), substr(\"hello\", 0), substr(\"hello\", -1, 3), \"[\" substr(\"hello\", 9) \"]\"}",
+            "",
+            "ell hello h []\n",
+        ],
+        [
+            "{n = gsub(/o/, \"[&]\"); print n, $0; sub(/l+/, \"\\\\&\", $2); print $2; s = \"aa\"; gsub(/a/, \"\\\\\\\\&\", s); print s}",
+            "hello world\n",
+            "2 hell[o] w[o]rld\nw[o]r&d\n\\a\\a\n",
+        ],
+        [
+            "BEGIN{print match(\"foobar\", /ob+/), RSTART, RLENGTH; print match(\"x\", \"y\"), RSTART, RLENGTH}",
+            "",
+            "3 3 2\n0 0 -1\n",
+        ],
+        [
+            "{n = split($0, a, \":\"); print n, a[1], a[n]; print split(\"  x  y \", w), w[2]; print split(\"a1b22c\", r, /[0-9]+/), r[3]}",
+            "a:b:c\n",
+            "3 a c\n2 y\n3 c\n",
+        ],
+        // 正規表現リテラルは一文字や空白でもEREとして扱う
+        [
+            "BEGIN{print split(\"a.b.c\", a, /./), split(\"a  b\", b, / /), split(\"a.b.c\", c, \".\"), split(\"a  b\", d, \" \")}",
+            "",
+            "6 3 3 2\n",
+        ],
+        ["BEGIN{x = \"abc\"; print gsub(/x*/, \"-\", x), x; y = \"abc\"; print sub(/z/, \"-\", y), y}", "", "4 -a-b-c-\n0 abc\n"],
+        ["{sub(/x/, \"y\", $5); print NF, $0}", "a b c\n", "3 a b c\n"],
+        // 置き換えやmatchも最左最長で行う
+        [
+            "BEGIN{s = \"xyz\"; sub(/x|xyz/, \"Q\", s); t = \"abab\"; n = gsub(/a|ab/, \"<&>\", t); print s, n, t, match(\"xabcd\", /b|bcd/), RLENGTH}",
+            "",
+            "Q 2 <ab><ab> 3 3\n",
+        ],
+        ["BEGIN{i = 0; a[i++] = \"xax\"; print sub(/a/, \"b\", a[--i]), i, a[0]}", "", "1 0 xbx\n"],
+        // printf / sprintf
+        [
+            "{printf \"%-6s|%8.2f|%03d\\n\", $1, $2, ++n}",
+            "apple 1.005\nkiwi 12\n",
+            "apple |    1.00|001\nkiwi  |   12.00|002\n",
+        ],
+        [
+            "BEGIN{printf(\"%s-%s%c\", \"a\", \"b\", 10); x = sprintf(\"%*d|%.3s|%x\", 4, 7, \"abcdef\", 255); print x}",
+            "",
+            "a-b\n   7|abc|ff\n",
+        ],
+        ["BEGIN{printf \"%d%% %e %g\\n\", 50, 1234.5, 0.0001}", "", "50% 1.234500e+03 0.0001\n"],
+        // 正規表現
+        ["$2 ~ /^err/ {print $1}", "a error\nb ok\nc err\n", "a\nc\n"],
+        ["$2 !~ /^err/ {print $1}", "a error\nb ok\nc err\n", "b\n"],
+        ["/o/ {print}", "foo\nbar\n", "foo\n"],
+        [
+            "{print $1 ~ \"^[[:alpha:]]{2,3}$\"}",
+            "ab\nabcd\na1\n",
+            "1\n0\n0\n",
+        ],
+        ["{print /^(cat|dog)s?$/}", "cats\ndog\ncow\n", "1\n1\n0\n"],
+        ["{print $0 ~ /[]\\/]/}", "a/b\na]b\nab\n", "1\n1\n0\n"],
+        // マッチは最左最長 (短い選択肢が先にあっても最長のものを選ぶ)
+        ["BEGIN{print match(\"abcd\", /ab|abcd/), RLENGTH}", "", "1 4\n"],
+        ["BEGIN{FS = \"1|12\"} {print NF, $2}", "a12b1c\n", "3 b\n"],
+        ["BEGIN{RS = \"AB|ABC\"} {print $0, RT}", "xABCyABz", "x ABC\ny AB\nz \n"],
+        ["BEGIN{print 6 / 2 / 3}", "", "1\n"],
+        // 単項演算子
+        [
+            "BEGIN{print -2^2, 2^-1, -(1-3), +\"3\", !0, !1}",
+            "",
+            "-4 0.5 2 3 1 0\n",
+        ],
+        [
+            "BEGIN{x = 3; print -x * 2, x - -1, x -1, - -x}",
+            "",
+            "-6 4 2 3\n",
+        ],
+        [
+            "BEGIN{x = 5; print --x, -x; y = 1; print !y == 0}",
+            "",
+            "4 -4\n1\n",
+        ],
+        ["{print !$1}", "\n1\n", "1\n0\n"],
+        // 三項演算子
+        ["{print ($1 > 5 ? \"big\" : \"small\")}", "3\n7\n", "small\nbig\n"],
+        ["BEGIN{x = 0 ? 1 : 0 ? 2 : 3; print x}", "", "3\n"],
+        ["BEGIN{1 ? n++ : m++; print n, m}", "", "1 \n"],
+        // in
+        [
+            "!($0 in seen) {seen[$0] = 1; print}",
+            "a\nb\na\nc\nb\n",
+            "a\nb\nc\n",
+        ],
+        ["BEGIN{if (\"x\" in a) print 1; print length(a[\"y\"]); y = (\"y\") in a; print y, \"x\" in a}", "", "0\n1 0\n"],
+        // for in / delete
+        [
+            "{sum[$1] += $2} END{for (k in sum) n++; print n, sum[\"a\"], sum[\"b\"]}",
+            "a 1\nb 2\na 3\n",
+            "2 4 2\n",
+        ],
+        [
+            "BEGIN{a[1]; a[2]; a[3]; for (k in a) {delete a; n++}; print n, length(a[1]), (1 in a)}",
+            "",
+            "1 0 1\n",
+        ],
+        [
+            "BEGIN{a[1]; a[2]; delete a[1]; print (1 in a), (2 in a)}",
+            "",
+            "0 1\n",
+        ],
+        [
+            "BEGIN{a[1]; a[2]; a[3]; for (k in a) {if (k == 2) continue; if (k == 2) print \"x\"; n++}; print n}",
+            "",
+            "2\n",
+        ],
+        [
+            "function f(arr) { for (k in a) return k } BEGIN{a[\"x\"]; for (i = 0; i < 3; i++) r = r f(); for (k in a) for (j in a) break; print r}",
+            "",
+            "xxx\n",
+        ],
+        // 多次元配列
+        [
+            "BEGIN{a[1, 2] = 3; print a[1, 2], a[1 SUBSEP 2], ((1, 2) in a), ((2, 1) in a)}",
+            "",
+            "3 3 1 0\n",
+        ],
+        [
+            "BEGIN{a[\"x\", \"y\"]; for (k in a) print k == \"x\\034y\"}",
+            "",
+            "1\n",
+        ],
+        [
+            "BEGIN{SUBSEP = \":\"; a[1, 2]; for (k in a) print k; delete a[1, 2]; print length(a[1 \":\" 2])}",
+            "",
+            "1:2\n0\n",
+        ],
+        // do-while文
+        ["BEGIN{i = 0; do {print i; i++} while (i < 3)}", "", "0\n1\n2\n"],
+        ["BEGIN{do print \"once\"; while (0)}", "", "once\n"],
+        ["BEGIN{do {i++} while ( i < 3 ); print i}", "", "3\n"],
+        ["BEGIN{while ( j < 2 ) j++; print j}", "", "2\n"],
+        [
+            "BEGIN{do {i++; if (i == 2) continue; if (i == 4) break; print i} while (i < 10); print \"end\", i}",
+            "",
+            "1\n3\nend 4\n",
+        ],
+        // next / nextfile
+        ["$1 == \"skip\" {next} {print}", "a\nskip\nb\n", "a\nb\n"],
+        [
+            "{for (k in a) next; a[$0]; print}",
+            "a\nb\n",
+            "a\n",
+        ],
+        ["/stop/ {nextfile} {print} END{print \"end\"}", "a\nstop\nb\n", "a\nend\n"],
+        // exit
+        ["BEGIN{print 1; exit; print 2} {print} END{print \"end\"}", "a\n", "1\nend\n"],
+        ["n == 1 {exit} {print; n++} END{print \"end\"}", "a\nb\nc\n", "a\nend\n"],
+        ["END{print 1; exit; print 2} END{print 3}", "", "1\n"],
+        [
+            "function f() {exit} BEGIN{f(); print \"no\"} END{print \"end\"; f(); print \"no\"}",
+            "",
+            "end\n",
+        ],
+        // getline
+        [
+            "BEGIN{while ((getline w < \"sample/wordlist\") > 0) n++; print n, w; print getline < \"/nonexistent\"}",
+            "",
+            "14 orange\n-1\n",
+        ],
+        [
+            "BEGIN{getline < \"sample/wordlist\"; print $0; getline < \"sample/wordlist\"; print}",
+            "",
+            "orange\norange\n",
+        ],
+        [
+            "{\"echo x y\" | getline; print $2; \"echo z\" | getline v; print v, $0; print (\"echo z\" | getline v)}",
+            "a\n",
+            "y\nz x y\n0\n",
+        ],
+        [
+            "!done {done = 1; getline; print \"after\", $0; getline z; print z, $0} {print $0} END{print getline, (getline z)}",
+            "1\n2\n3\n4\n",
+            "after 2\n3 2\n2\n4\n0 0\n",
+        ],
+        ["BEGIN{while (getline line) n++; print n, line}", "a\nb\n", "2 b\n"],
+        // 代入先の添字は一度だけ評価し，読み込みに失敗したら変数は変わらない
+        [
+            "BEGIN{getline a[i++] < \"sample/wordlist\"; for (k in a) n++; print i, n, a[0]; x = \"keep\"; getline x < \"/nonexistent\"; print x}",
+            "",
+            "1 1 orange\nkeep\n",
+        ],
+        // 出力先の指定
+        [
+            "{print $1 | \"sort\"} END{print close(\"sort\"), close(\"sort\")}",
+            "b\nc\na\n",
+            "a\nb\nc\n0 -1\n",
+        ],
+        [
+            "BEGIN{print \"x\" | \"cat >/dev/null; exit 3\"; print close(\"cat >/dev/null; exit 3\")}",
+            "",
+            "3\n",
+        ],
+        [
+            "BEGIN{print 1, 2 > \"/dev/stdout\"; print(3) > \"-\"; printf \"e\" > \"/dev/stderr\"; print (1 > 2)}",
+            "",
+            "1 2\n3\n0\n",
+        ],
+        // 範囲パターン
+        [
+            "/^start/, /^end/",
+            "a\nstart\nb\nend\nc\nstart\nd\n",
+            "start\nb\nend\nstart\nd\n",
+        ],
+        [
+            "$1 == \"x\", $1 == \"x\" {print $2}",
+            "x 1\ny 2\nx 3\n",
+            "1\n3\n",
+        ],
+        [
+            "/b/,/c/ {print \"1:\" $0}\n/c/,/d/ {print \"2:\" $0}",
+            "a\nb\nc\nd\ne\n",
+            "1:b\n1:c\n2:c\n2:d\n",
+        ], // [
+           //     "
+           //     BEGIN {
+           //       for (i=0; i<10;) {
+           //         print i
+           //         i += 1
+           //       }
+           //     }
+           //     ",
+           //     "",
+           //     "0\n1\n2\n3\n4\n5\n6\n7\n8\n9\n"
+           // ],
+           // [
+           //     "",
+           //     "",
+           //     ""
+           // ],
     ];
     for set in test_sets {
         dbg!(&set);
@@ -198,3 +490,221 @@ fn test_my_command() {
         assert.success().stdout(set[2]);
     }
 }
+
+#[test]
+fn test_compile_error() {
+    let programs = [
+        "BEGIN{next}",
+        "END{next}",
+        "function f() {next} {f()}",
+        "BEGIN{nextfile}",
+        "{delete $1}",
+        "BEGIN{PROCINFO[\"pid\"] = 1}",
+        "BEGIN{delete PROCINFO}",
+        "BEGIN{split(\"a b\", PROCINFO)}",
+    ];
+    for prog in programs {
+        let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+        cmd.arg(prog);
+        let output = cmd.output().unwrap();
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Compile Error"));
+    }
+}
+
+#[test]
+fn test_exit_status() {
+    let test_sets = [
+        ("BEGIN{exit 3}", 3),
+        ("BEGIN{exit}", 0),
+        ("BEGIN{exit 1} END{exit}", 1),
+        ("BEGIN{exit 1} END{exit 4}", 4),
+        ("{exit $1} END{}", 5),
+    ];
+    for (prog, code) in test_sets {
+        let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+        cmd.arg(prog);
+        cmd.write_stdin("5\n6\n");
+        cmd.assert().code(code);
+    }
+}
+
+#[test]
+fn test_redirect() {
+    let dir = std::env::temp_dir().join(format!("kawk_test_redirect_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let prog = format!(
+        "{{ print $2 > \"{0}/\" $1 \".txt\" }} END {{ print \"end\" >> \"{0}/a.txt\"; close(\"{0}/a.txt\"); close(\"{0}/b.txt\"); print \"new\" > \"{0}/b.txt\" }}",
+        dir.display()
+    );
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.arg(prog);
+    cmd.write_stdin("a 1\nb 2\na 3\n");
+    cmd.assert().success().stdout("");
+    // 開いている間は > でも追記され，close()の後に > で開き直すと切り詰められる
+    assert_eq!(
+        "1\n3\nend\n",
+        std::fs::read_to_string(dir.join("a.txt")).unwrap()
+    );
+    assert_eq!("new\n", std::fs::read_to_string(dir.join("b.txt")).unwrap());
+
+    // 致命的なエラーで終了してもそれまでの出力は書き出される
+    let prog = format!(
+        "{{ print > \"{0}/c.txt\"; print \"out\" }} END {{ x = $(-1) }}",
+        dir.display()
+    );
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.arg(prog);
+    cmd.write_stdin("line\n");
+    cmd.assert().code(2).stdout("out\n");
+    assert_eq!(
+        "line\n",
+        std::fs::read_to_string(dir.join("c.txt")).unwrap()
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_options() {
+    let test_sets: [(&[&str], &str, &str); 8] = [
+        (&["-F:", "{print $2}"], "a:b:c\n", "b\n"),
+        (&["-F", "\\t", "{print $2}"], "a b\tc\n", "c\n"),
+        (&["-Ft", "{print $2}"], "a b\tc\n", "c\n"),
+        (&["-F", "[,;]", "{print $3}"], "a,b;c\n", "c\n"),
+        // -v はBEGINの前に代入され，エスケープシーケンスが解釈される
+        (
+            &["-v", "x=1", "-v", "y=a\\tb", "BEGIN{print x, y}"],
+            "",
+            "1 a\tb\n",
+        ),
+        (&["-v", "OFS=-", "{print $1, $2}"], "a b\n", "a-b\n"),
+        (&["--", "{print}", "-"], "a\n", "a\n"),
+        (&["--", "BEGIN{print ARGV[1]}", "-x"], "", "-x\n"),
+    ];
+    for (args, stdin, expect) in test_sets {
+        let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+        cmd.args(args);
+        cmd.write_stdin(stdin);
+        cmd.assert().success().stdout(expect);
+    }
+}
+
+#[test]
+fn test_operands() {
+    let dir = std::env::temp_dir().join(format!("kawk_test_operands_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("f1"), "a\nb\n").unwrap();
+    std::fs::write(dir.join("f2"), "c\n").unwrap();
+    let f1 = dir.join("f1").display().to_string();
+    let f2 = dir.join("f2").display().to_string();
+    let prog = "{print FNR, NR, $0, x}";
+    let test_sets: [(Vec<&str>, &str, &str); 5] = [
+        // var=value はその位置で代入される
+        (vec![prog, &f1, "x=7", &f2], "", "1 1 a \n2 2 b \n1 3 c 7\n"),
+        // - は標準入力
+        (vec![prog, &f2, "-"], "s\n", "1 1 c \n1 2 s \n"),
+        // ファイルがなければ標準入力を読む
+        (vec![prog, "x=\\tv"], "s\n", "1 1 s \tv\n"),
+        // BEGINでARGVを変更できる
+        (
+            vec![
+                "BEGIN{ARGV[1] = \"\"; ARGV[ARGC++] = \"x=1\"} END{print x, NR}",
+                &f1,
+            ],
+            "s\n",
+            "1 1\n",
+        ),
+        (
+            vec![
+                "BEGIN{for (i = 0; i < ARGC; i++) print i, ARGV[i]}",
+                "a",
+                "-x",
+            ],
+            "",
+            "0 kawk\n1 a\n2 -x\n",
+        ),
+    ];
+    for (args, stdin, expect) in test_sets {
+        let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+        cmd.args(&args);
+        cmd.write_stdin(stdin);
+        cmd.assert().success().stdout(expect);
+    }
+    // FILENAMEはファイルを開くごとに変わる
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.args(["FNR == 1 {print FILENAME}", &f1, &f2]);
+    cmd.assert().success().stdout(format!("{}\n{}\n", f1, f2));
+    // 開けないファイルは飛ばし，終了ステータスは2になる
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.args(["{print}", &dir.join("none").display().to_string(), &f2]);
+    cmd.assert().code(2).stdout("c\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_progfiles() {
+    let dir = std::env::temp_dir().join(format!("kawk_test_progfiles_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("lib.awk"), "function twice(x) { return x * 2 }").unwrap();
+    std::fs::write(dir.join("main.awk"), "BEGIN { print twice(n) }").unwrap();
+    // 複数の-fはつなげて一つのプログラムになる
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.args(["-v", "n=21", "-f"]);
+    cmd.arg(dir.join("lib.awk"));
+    cmd.arg("-f");
+    cmd.arg(dir.join("main.awk"));
+    cmd.assert().success().stdout("42\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // 使い方の誤りは終了ステータス2
+    for args in [&[][..], &["-Q", "{print}"], &["-v", "1x=1", "BEGIN{}"]] {
+        let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+        cmd.args(args);
+        cmd.assert().code(2).stdout("");
+    }
+
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.arg("--version");
+    cmd.assert()
+        .success()
+        .stdout(format!("kawk {}\n", env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn test_builtin_arrays() {
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.env("KAWK_TEST_ENV", "deploy");
+    cmd.arg("BEGIN{print ENVIRON[\"KAWK_TEST_ENV\"], (\"KAWK_NO_SUCH_ENV\" in ENVIRON)}");
+    cmd.assert().success().stdout("deploy 0\n");
+
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.arg("BEGIN{print PROCINFO[\"version\"], PROCINFO[\"FS\"], PROCINFO[\"platform\"], (PROCINFO[\"pid\"] > 0), PROCINFO[\"uid\"]}");
+    cmd.assert().success().stdout(format!(
+        "{} FS posix 1 {}\n",
+        env!("CARGO_PKG_VERSION"),
+        unsafe { libc::getuid() }
+    ));
+
+    // 参照しても要素は作られない
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.arg("BEGIN{x = PROCINFO[\"zz\"]; print (\"zz\" in PROCINFO), length(x)}");
+    cmd.assert().success().stdout("0 0\n");
+}
+
+#[test]
+fn test_large_records() {
+    // 空行のない大きな入力を段落モードで読む (レコードが長くなっても読み込みは線形時間)
+    let input: String = (0..100000).map(|i| format!("line {}\n", i)).collect();
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.arg("BEGIN{RS = \"\"} {print NR, NF, $NF}");
+    cmd.write_stdin(input.clone());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success().stdout("1 200000 99999\n");
+
+    // 一行に多数のレコードがある
+    let input: String = (0..200000).map(|i| format!("r{};", i)).collect();
+    let mut cmd = Command::cargo_bin("kawk").expect("Failed to find binary");
+    cmd.arg("BEGIN{RS = \";\"} END{print NR, $0}");
+    cmd.write_stdin(input);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success().stdout("200000 r199999\n");
+}